@@ -1,3 +1,4 @@
+use crate::{color::ColorChoice, config::Arch};
 use anyhow::{anyhow, Context, Result};
 use std::{
     mem,
@@ -5,6 +6,7 @@ use std::{
 };
 
 /// Internal representation of the `cargo bootimage` command.
+#[allow(clippy::large_enum_variant)]
 pub enum BuildCommand {
     /// A normal invocation (i.e. no `--help` or `--version`)
     Build(BuildArgs),
@@ -12,6 +14,8 @@ pub enum BuildCommand {
     Version,
     /// The `--help` command
     Help,
+    /// The `init` subcommand with the directory of the new project
+    Init(PathBuf),
 }
 
 impl BuildCommand {
@@ -20,9 +24,33 @@ impl BuildCommand {
     where
         A: Iterator<Item = String>,
     {
+        let mut args = args.peekable();
+        if args.peek().map(String::as_str) == Some("init") {
+            args.next();
+            return parse_init_args(args);
+        }
+
         let mut manifest_path: Option<PathBuf> = None;
         let mut cargo_args = Vec::new();
         let mut quiet = false;
+        let mut color = None;
+        let mut bin = None;
+        let mut profile = None;
+        let mut example = None;
+        let mut packages = Vec::new();
+        let mut metadata_options = Vec::new();
+        let mut config_overrides = Vec::new();
+        let mut debug_artifacts = false;
+        let mut write_to = None;
+        let mut manifest_out = None;
+        let mut force = false;
+        let mut print_config = false;
+        let mut list_images = false;
+        let mut deny_warnings = false;
+        let mut reuse_target_artifacts = false;
+        let mut arch = None;
+        let mut run = false;
+        let mut run_args = Vec::new();
         {
             fn set<T>(arg: &mut Option<T>, value: Option<T>) -> Result<()> {
                 let previous = mem::replace(arg, value);
@@ -44,14 +72,151 @@ impl BuildCommand {
                     "--quiet" => {
                         quiet = true;
                     }
+                    "--debug-artifacts" => {
+                        debug_artifacts = true;
+                    }
+                    "--print-config" => {
+                        print_config = true;
+                    }
+                    "--list-images" => {
+                        list_images = true;
+                    }
+                    "--deny-warnings" => {
+                        deny_warnings = true;
+                    }
+                    "--reuse-target-artifacts" => {
+                        reuse_target_artifacts = true;
+                    }
+                    "--run" => {
+                        run = true;
+                    }
+                    "--" => {
+                        run_args.extend(arg_iter.by_ref());
+                    }
+                    "--arch" => {
+                        let value = arg_iter
+                            .next()
+                            .ok_or_else(|| anyhow!("--arch expects an architecture"))?;
+                        set(&mut arch, Some(value.parse()?))?;
+                    }
+                    _ if arg.starts_with("--arch=") => {
+                        set(&mut arch, Some(arg.trim_start_matches("--arch=").parse()?))?;
+                    }
+                    "--force" => {
+                        force = true;
+                    }
+                    "--write-to" => {
+                        let path = arg_iter
+                            .next()
+                            .ok_or_else(|| anyhow!("--write-to expects a device path"))?;
+                        set(&mut write_to, Some(PathBuf::from(path)))?;
+                    }
+                    _ if arg.starts_with("--write-to=") => {
+                        set(
+                            &mut write_to,
+                            Some(PathBuf::from(arg.trim_start_matches("--write-to="))),
+                        )?;
+                    }
+                    "--manifest-out" => {
+                        let path = arg_iter
+                            .next()
+                            .ok_or_else(|| anyhow!("--manifest-out expects a path"))?;
+                        set(&mut manifest_out, Some(PathBuf::from(path)))?;
+                    }
+                    _ if arg.starts_with("--manifest-out=") => {
+                        set(
+                            &mut manifest_out,
+                            Some(PathBuf::from(arg.trim_start_matches("--manifest-out="))),
+                        )?;
+                    }
+                    "--locked" | "--frozen" | "--offline" => {
+                        // also passed to `cargo metadata` to keep it from updating the lockfile
+                        metadata_options.push(arg.clone());
+                        cargo_args.push(arg);
+                    }
+                    "--config" => {
+                        let next = arg_iter.next();
+                        config_overrides
+                            .extend(next.iter().map(|value| format!("--config={}", value)));
+                        cargo_args.push(arg);
+                        cargo_args.extend(next);
+                    }
+                    _ if arg.starts_with("--config=") => {
+                        config_overrides.push(arg.clone());
+                        cargo_args.push(arg);
+                    }
+                    "--color" => {
+                        let next = arg_iter.next();
+                        let value = next
+                            .as_deref()
+                            .ok_or_else(|| anyhow!("--color expects a value"))?;
+                        set(&mut color, Some(value.parse()?))?;
+                        // also forward the argument to cargo for consistent output
+                        cargo_args.push(arg);
+                        cargo_args.extend(next);
+                    }
+                    "--release" => {
+                        set(&mut profile, Some("release".to_owned()))?;
+                        cargo_args.push(arg);
+                    }
+                    "--profile" => {
+                        let next = arg_iter.next();
+                        set(&mut profile, next.clone())?;
+                        cargo_args.push(arg);
+                        cargo_args.extend(next);
+                    }
+                    _ if arg.starts_with("--profile=") => {
+                        set(
+                            &mut profile,
+                            Some(arg.trim_start_matches("--profile=").to_owned()),
+                        )?;
+                        cargo_args.push(arg);
+                    }
+                    "--bin" => {
+                        let next = arg_iter.next();
+                        set(&mut bin, next.clone())?;
+                        cargo_args.push(arg);
+                        cargo_args.extend(next);
+                    }
+                    _ if arg.starts_with("--bin=") => {
+                        set(&mut bin, Some(arg.trim_start_matches("--bin=").to_owned()))?;
+                        cargo_args.push(arg);
+                    }
+                    "--package" | "-p" => {
+                        let next = arg_iter.next();
+                        packages.extend(next.clone());
+                        cargo_args.push(arg);
+                        cargo_args.extend(next);
+                    }
+                    _ if arg.starts_with("--package=") => {
+                        packages.push(arg.trim_start_matches("--package=").to_owned());
+                        cargo_args.push(arg);
+                    }
+                    "--example" => {
+                        let next = arg_iter.next();
+                        set(&mut example, next.clone())?;
+                        cargo_args.push(arg);
+                        cargo_args.extend(next);
+                    }
+                    _ if arg.starts_with("--example=") => {
+                        set(
+                            &mut example,
+                            Some(arg.trim_start_matches("--example=").to_owned()),
+                        )?;
+                        cargo_args.push(arg);
+                    }
+                    _ if arg.starts_with("--color=") => {
+                        set(
+                            &mut color,
+                            Some(arg.trim_start_matches("--color=").parse()?),
+                        )?;
+                        cargo_args.push(arg);
+                    }
                     "--manifest-path" => {
                         let next = arg_iter.next();
                         set(
                             &mut manifest_path,
-                            next.as_ref()
-                                .map(|p| Path::new(&p).canonicalize())
-                                .transpose()
-                                .context("--manifest-path invalid")?,
+                            next.as_deref().map(parse_manifest_path).transpose()?,
                         )?;
                         cargo_args.push(arg);
                         if let Some(next) = next {
@@ -59,9 +224,7 @@ impl BuildCommand {
                         }
                     }
                     _ if arg.starts_with("--manifest-path=") => {
-                        let path = Path::new(arg.trim_start_matches("--manifest-path="))
-                            .canonicalize()
-                            .context("--manifest-path invalid")?;
+                        let path = parse_manifest_path(arg.trim_start_matches("--manifest-path="))?;
                         set(&mut manifest_path, Some(path))?;
                         cargo_args.push(arg);
                     }
@@ -71,15 +234,83 @@ impl BuildCommand {
                 };
             }
         }
+        if !run && !run_args.is_empty() {
+            // without `--run`, the arguments after `--` are meant for cargo
+            cargo_args.push("--".into());
+            cargo_args.append(&mut run_args);
+        }
 
         Ok(BuildCommand::Build(BuildArgs {
             manifest_path,
             cargo_args,
             quiet,
+            color,
+            bin,
+            profile,
+            example,
+            packages,
+            metadata_options,
+            config_overrides,
+            debug_artifacts,
+            write_to,
+            manifest_out,
+            force,
+            print_config,
+            list_images,
+            deny_warnings,
+            reuse_target_artifacts,
+            arch,
+            run,
+            run_args,
         }))
     }
 }
 
+/// Parses the arguments of `cargo bootimage init [PATH]`.
+fn parse_init_args<A>(args: A) -> Result<BuildCommand>
+where
+    A: Iterator<Item = String>,
+{
+    let mut path = None;
+    for arg in args {
+        match arg.as_ref() {
+            "--help" | "-h" => return Ok(BuildCommand::Help),
+            _ if arg.starts_with('-') => {
+                return Err(anyhow!("unexpected argument `{}` for `init`", arg));
+            }
+            _ => {
+                if path.replace(PathBuf::from(&arg)).is_some() {
+                    return Err(anyhow!("`init` expects at most one path"));
+                }
+            }
+        }
+    }
+    Ok(BuildCommand::Init(
+        path.unwrap_or_else(|| PathBuf::from(".")),
+    ))
+}
+
+/// Canonicalizes the given `--manifest-path` value and checks that it points to a
+/// `Cargo.toml` file.
+fn parse_manifest_path(path: &str) -> Result<PathBuf> {
+    let path = Path::new(path)
+        .canonicalize()
+        .context("--manifest-path invalid")?;
+    if path.is_dir() {
+        return Err(anyhow!(
+            "--manifest-path invalid: expected path to a Cargo.toml file, got directory `{}`",
+            path.display()
+        ));
+    }
+    if path.file_name() != Some("Cargo.toml".as_ref()) {
+        return Err(anyhow!(
+            "--manifest-path invalid: expected path to a Cargo.toml file, got `{}`",
+            path.display()
+        ));
+    }
+    Ok(path)
+}
+
 /// Arguments passed to `cargo bootimage`.
 #[derive(Debug, Clone)]
 pub struct BuildArgs {
@@ -89,6 +320,42 @@ pub struct BuildArgs {
     cargo_args: Vec<String>,
     /// Suppress any output to stdout.
     quiet: bool,
+    /// The value of the `--color` argument (also present in `cargo_args`).
+    color: Option<ColorChoice>,
+    /// The value of the `--bin` argument (also present in `cargo_args`).
+    bin: Option<String>,
+    /// The profile set through `--release` or `--profile` (also present in `cargo_args`).
+    profile: Option<String>,
+    /// The value of the `--example` argument (also present in `cargo_args`).
+    example: Option<String>,
+    /// The values of the `--package` arguments (also present in `cargo_args`).
+    packages: Vec<String>,
+    /// The `--locked`, `--frozen`, and `--offline` flags (also present in `cargo_args`).
+    metadata_options: Vec<String>,
+    /// The `--config` arguments as `--config=VALUE` (also present in `cargo_args`).
+    config_overrides: Vec<String>,
+    /// Print the artifacts reported by cargo to stderr.
+    debug_artifacts: bool,
+    /// The device that the disk image should be written to.
+    write_to: Option<PathBuf>,
+    /// The path of the JSON file that lists all created artifacts.
+    manifest_out: Option<PathBuf>,
+    /// Confirms writing to the `write_to` device.
+    force: bool,
+    /// Print the effective configuration instead of building.
+    print_config: bool,
+    /// Print the paths of the disk images that would be created instead of building.
+    list_images: bool,
+    /// Fail the build if the kernel or bootloader build emitted warnings.
+    deny_warnings: bool,
+    /// Build the bootloader in the target directory of the kernel (like `shared-target-dir`).
+    reuse_target_artifacts: bool,
+    /// The architecture set through `--arch`.
+    arch: Option<Arch>,
+    /// Run the created disk image after building.
+    run: bool,
+    /// The arguments after `--`, which are passed to the run command if `run` is set.
+    run_args: Vec<String>,
 }
 
 impl BuildArgs {
@@ -99,11 +366,129 @@ impl BuildArgs {
 
     /// Arguments that should be forwarded to `cargo build`.
     pub fn cargo_args(&self) -> &[String] {
-        &self.cargo_args.as_ref()
+        self.cargo_args.as_ref()
     }
 
     /// Whether a `--quiet` flag was passed.
     pub fn quiet(&self) -> bool {
         self.quiet
     }
+
+    /// The value of the `--bin` argument, if any.
+    pub fn bin(&self) -> Option<&str> {
+        self.bin.as_deref()
+    }
+
+    /// The value of the `--example` argument, if any.
+    pub fn example(&self) -> Option<&str> {
+        self.example.as_deref()
+    }
+
+    /// The values of the `--package` (or `-p`) arguments.
+    pub fn packages(&self) -> &[String] {
+        &self.packages
+    }
+
+    /// The `--locked`, `--frozen`, and `--offline` flags, which also apply to `cargo metadata`.
+    pub fn metadata_options(&self) -> &[String] {
+        &self.metadata_options
+    }
+
+    /// The `--config` arguments (as `--config=VALUE`), which also apply to the bootloader build.
+    pub fn config_overrides(&self) -> &[String] {
+        &self.config_overrides
+    }
+
+    /// Whether a `--debug-artifacts` flag was passed.
+    pub fn debug_artifacts(&self) -> bool {
+        self.debug_artifacts
+    }
+
+    /// The value of the `--write-to` argument, if any.
+    pub fn write_to(&self) -> Option<&Path> {
+        self.write_to.as_deref()
+    }
+
+    /// The value of the `--manifest-out` argument, if any.
+    pub fn manifest_out(&self) -> Option<&Path> {
+        self.manifest_out.as_deref()
+    }
+
+    /// Whether a `--force` flag was passed.
+    pub fn force(&self) -> bool {
+        self.force
+    }
+
+    /// The cargo profile of the build (`release` for `--release`, `dev` by default).
+    pub fn profile(&self) -> &str {
+        self.profile.as_deref().unwrap_or("dev")
+    }
+
+    /// Whether a `--print-config` flag was passed.
+    pub fn print_config(&self) -> bool {
+        self.print_config
+    }
+
+    /// Whether a `--list-images` flag was passed.
+    pub fn list_images(&self) -> bool {
+        self.list_images
+    }
+
+    /// Whether a `--deny-warnings` flag was passed.
+    pub fn deny_warnings(&self) -> bool {
+        self.deny_warnings
+    }
+
+    /// Whether a `--reuse-target-artifacts` flag was passed.
+    pub fn reuse_target_artifacts(&self) -> bool {
+        self.reuse_target_artifacts
+    }
+
+    /// Whether a `--run` flag was passed.
+    pub fn run(&self) -> bool {
+        self.run
+    }
+
+    /// The arguments after `--` that are passed to the run command for `--run`.
+    pub fn run_args(&self) -> &[String] {
+        &self.run_args
+    }
+
+    /// The value of the `--arch` argument, if any.
+    pub fn arch(&self) -> Option<Arch> {
+        self.arch
+    }
+
+    /// The value of the `--color` argument, if any.
+    pub fn color(&self) -> Option<ColorChoice> {
+        self.color
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> BuildArgs {
+        match BuildCommand::parse_args(args.iter().map(|&arg| arg.to_owned())).unwrap() {
+            BuildCommand::Build(args) => args,
+            _ => panic!("expected a build command"),
+        }
+    }
+
+    #[test]
+    fn metadata_options() {
+        let args = parse(&["--locked", "--offline", "--", "--frozen"]);
+        assert_eq!(args.metadata_options(), ["--locked", "--offline"]);
+        assert_eq!(
+            args.cargo_args(),
+            ["--locked", "--offline", "--", "--frozen"]
+        );
+    }
+
+    #[test]
+    fn config_overrides() {
+        let args = parse(&["--config", "a=1", "--config=b=2", "--", "--config=c=3"]);
+        assert_eq!(args.config_overrides(), ["--config=a=1", "--config=b=2"]);
+    }
 }
@@ -1,5 +1,6 @@
-use anyhow::{anyhow, Result};
-use std::path::PathBuf;
+use crate::config::Arch;
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
 
 /// Internal representation of the `bootimage runner` command.
 pub enum RunnerCommand {
@@ -19,6 +20,17 @@ impl RunnerCommand {
     {
         let mut executable = None;
         let mut quiet = false;
+        let mut emit_qemu_command_file = None;
+        let mut dry_run = false;
+        let mut firmware = None;
+        let mut run_dir = None;
+        let mut qemu_start_delay = None;
+        let mut timeout = None;
+        let mut print_serial_on_failure = false;
+        let mut no_shutdown = false;
+        let mut debug_artifacts = false;
+        let mut initrd = None;
+        let mut arch = None;
         let mut runner_args = None;
 
         let mut arg_iter = args.fuse();
@@ -45,28 +57,282 @@ impl RunnerCommand {
                 "--quiet" => {
                     quiet = true;
                 }
+                "--dry-run" => {
+                    dry_run = true;
+                }
+                "--print-serial-on-failure" => {
+                    print_serial_on_failure = true;
+                }
+                "--no-shutdown" => {
+                    no_shutdown = true;
+                }
+                "--debug-artifacts" => {
+                    debug_artifacts = true;
+                }
+                "--bios" => {
+                    set_firmware(&mut firmware, Firmware::Bios)?;
+                }
+                "--uefi" => {
+                    set_firmware(&mut firmware, Firmware::Uefi)?;
+                }
+                "--firmware" => {
+                    let path = arg_iter
+                        .next()
+                        .ok_or_else(|| anyhow!("--firmware expects a path"))?;
+                    set_firmware(&mut firmware, Firmware::Custom(PathBuf::from(path)))?;
+                }
+                arg if arg.starts_with("--firmware=") => {
+                    let path = arg.trim_start_matches("--firmware=");
+                    set_firmware(&mut firmware, Firmware::Custom(PathBuf::from(path)))?;
+                }
+                "--run-dir" => {
+                    let path = arg_iter
+                        .next()
+                        .ok_or_else(|| anyhow!("--run-dir expects a path"))?;
+                    run_dir = Some(PathBuf::from(path));
+                }
+                arg if arg.starts_with("--run-dir=") => {
+                    run_dir = Some(PathBuf::from(arg.trim_start_matches("--run-dir=")));
+                }
+                "--qemu-start-delay" => {
+                    let delay = arg_iter
+                        .next()
+                        .ok_or_else(|| anyhow!("--qemu-start-delay expects milliseconds"))?;
+                    qemu_start_delay = Some(parse_delay(&delay)?);
+                }
+                arg if arg.starts_with("--qemu-start-delay=") => {
+                    let delay = arg.trim_start_matches("--qemu-start-delay=");
+                    qemu_start_delay = Some(parse_delay(delay)?);
+                }
+                "--timeout" => {
+                    let value = arg_iter
+                        .next()
+                        .ok_or_else(|| anyhow!("--timeout expects seconds"))?;
+                    timeout = Some(parse_timeout(&value)?);
+                }
+                arg if arg.starts_with("--timeout=") => {
+                    timeout = Some(parse_timeout(arg.trim_start_matches("--timeout="))?);
+                }
+                "--arch" => {
+                    let value = arg_iter
+                        .next()
+                        .ok_or_else(|| anyhow!("--arch expects an architecture"))?;
+                    arch = Some(value.parse()?);
+                }
+                arg if arg.starts_with("--arch=") => {
+                    arch = Some(arg.trim_start_matches("--arch=").parse()?);
+                }
+                "--initrd" => {
+                    let path = arg_iter
+                        .next()
+                        .ok_or_else(|| anyhow!("--initrd expects a path"))?;
+                    initrd = Some(PathBuf::from(path));
+                }
+                arg if arg.starts_with("--initrd=") => {
+                    initrd = Some(PathBuf::from(arg.trim_start_matches("--initrd=")));
+                }
+                "--emit-qemu-command-file" => {
+                    let path = arg_iter
+                        .next()
+                        .ok_or_else(|| anyhow!("--emit-qemu-command-file expects a path"))?;
+                    emit_qemu_command_file = Some(PathBuf::from(path));
+                }
+                arg if arg.starts_with("--emit-qemu-command-file=") => {
+                    let path = arg.trim_start_matches("--emit-qemu-command-file=");
+                    emit_qemu_command_file = Some(PathBuf::from(path));
+                }
                 exe => {
                     executable = Some(PathBuf::from(exe));
                 }
             }
         }
 
+        let executable = executable
+            .ok_or_else(|| anyhow!("excepted path to kernel executable as first argument"))?;
+        let target_triple = std::env::var("TARGET")
+            .ok()
+            .or_else(|| target_triple_from_path(&executable));
+
         Ok(Self::Runner(RunnerArgs {
-            executable: executable
-                .ok_or_else(|| anyhow!("excepted path to kernel executable as first argument"))?,
+            executable,
+            target_triple,
             quiet,
+            emit_qemu_command_file,
+            dry_run,
+            firmware,
+            run_dir,
+            qemu_start_delay,
+            timeout,
+            print_serial_on_failure,
+            no_shutdown,
+            debug_artifacts,
+            initrd,
+            arch,
             runner_args,
         }))
     }
 }
 
+/// Derives the target triple from the output directory of the given executable.
+///
+/// Cargo places executables for explicitly given targets in `target/<triple>/<profile>`.
+/// Returns `None` if the executable is in `target/<profile>` (i.e. built for the host),
+/// which is detected through the `CACHEDIR.TAG` file in the target directory, or for
+/// doctests.
+pub fn target_triple_from_path(executable: &Path) -> Option<String> {
+    let profile_dir = profile_dir(executable)?;
+    if profile_dir
+        .file_name()?
+        .to_str()?
+        .starts_with("rustdoctest")
+    {
+        return None;
+    }
+    let triple_dir = profile_dir.parent()?;
+    if triple_dir.join("CACHEDIR.TAG").exists() {
+        return None;
+    }
+    triple_dir.file_name()?.to_str().map(String::from)
+}
+
+/// Returns the profile output directory (e.g. `target/<triple>/debug`) of the executable.
+pub(crate) fn profile_dir(executable: &Path) -> Option<&Path> {
+    let dir = executable.parent()?;
+    if dir.ends_with("deps") || dir.ends_with("examples") {
+        dir.parent()
+    } else {
+        Some(dir)
+    }
+}
+
+fn parse_delay(delay: &str) -> Result<u64> {
+    delay
+        .parse()
+        .map_err(|_| anyhow!("--qemu-start-delay expects milliseconds, got `{}`", delay))
+}
+
+fn parse_timeout(timeout: &str) -> Result<u32> {
+    timeout
+        .parse()
+        .map_err(|_| anyhow!("--timeout expects seconds, got `{}`", timeout))
+}
+
+fn set_firmware(firmware: &mut Option<Firmware>, value: Firmware) -> Result<()> {
+    if firmware.replace(value).is_some() {
+        return Err(anyhow!(
+            "only one of `--bios`, `--uefi`, and `--firmware` can be specified"
+        ));
+    }
+    Ok(())
+}
+
 /// Arguments for the `bootimage runner` command
 #[derive(Debug, Clone)]
 pub struct RunnerArgs {
     /// Path to the executable binary
     pub executable: PathBuf,
+    /// The target triple (or custom target name) that the executable was built for
+    ///
+    /// Taken from the `TARGET` environment variable if set and derived from the path of the
+    /// executable otherwise. `None` for executables built for the host.
+    pub target_triple: Option<String>,
     /// Suppress any output to stdout.
     pub quiet: bool,
+    /// Write the fully substituted run command to a shell script at this path.
+    pub emit_qemu_command_file: Option<PathBuf>,
+    /// Don't launch the run command, only print (and optionally emit) it.
+    pub dry_run: bool,
+    /// The firmware that QEMU should boot the disk image with.
+    pub firmware: Option<Firmware>,
+    /// The working directory for the run command (overrides `run-working-dir`).
+    pub run_dir: Option<PathBuf>,
+    /// Milliseconds to wait before launching QEMU (overrides `qemu-start-delay`).
+    pub qemu_start_delay: Option<u64>,
+    /// The timeout for test executables in seconds (overrides `test-timeout` and
+    /// `test-timeouts`).
+    pub timeout: Option<u32>,
+    /// Write the serial output of tests to a file and print it if the test fails.
+    pub print_serial_on_failure: bool,
+    /// Pass `-no-shutdown` to QEMU and wait for tests without a timeout.
+    pub no_shutdown: bool,
+    /// Print the artifacts reported by cargo during the bootloader build to stderr.
+    pub debug_artifacts: bool,
+    /// The initial ramdisk that is passed to QEMU (overrides `initrd`).
+    pub initrd: Option<PathBuf>,
+    /// The architecture of the executable (overrides `arch` and the inferred architecture).
+    pub arch: Option<Arch>,
     /// Additional arguments passed to the runner
     pub runner_args: Option<Vec<String>>,
 }
+
+impl RunnerArgs {
+    /// Creates runner arguments for the given executable with default options.
+    pub fn new(executable: PathBuf) -> Self {
+        RunnerArgs {
+            target_triple: target_triple_from_path(&executable),
+            executable,
+            quiet: false,
+            emit_qemu_command_file: None,
+            dry_run: false,
+            firmware: None,
+            run_dir: None,
+            qemu_start_delay: None,
+            timeout: None,
+            print_serial_on_failure: false,
+            no_shutdown: false,
+            debug_artifacts: false,
+            initrd: None,
+            arch: None,
+            runner_args: None,
+        }
+    }
+
+    /// Like [`new`](RunnerArgs::new), but canonicalizes the executable path first.
+    ///
+    /// Returns an error if the executable does not exist.
+    pub fn with_canonicalized_executable(executable: &Path) -> Result<Self> {
+        let executable = executable.canonicalize().with_context(|| {
+            format!(
+                "failed to canonicalize executable path `{}`",
+                executable.display()
+            )
+        })?;
+        Ok(Self::new(executable))
+    }
+
+    /// Returns the cargo profile that the executable was built with.
+    ///
+    /// The profile is derived from the output directory of the executable (e.g.
+    /// `target/<triple>/release`). Returns `None` if it can't be determined (e.g. for
+    /// doctests).
+    pub fn profile(&self) -> Option<String> {
+        match profile_dir(&self.executable)?.file_name()?.to_str()? {
+            "debug" => Some("dev".into()),
+            name if name.starts_with("rustdoctest") => None,
+            name => Some(name.into()),
+        }
+    }
+
+    /// Sets whether output to stdout should be suppressed.
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Sets the additional arguments that are passed to the run command.
+    pub fn runner_args(mut self, runner_args: Vec<String>) -> Self {
+        self.runner_args = Some(runner_args);
+        self
+    }
+}
+
+/// The firmware selected through the `--bios`, `--uefi`, or `--firmware` arguments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Firmware {
+    /// Boot the BIOS disk image with QEMU's default firmware.
+    Bios,
+    /// Boot the UEFI disk image with the OVMF firmware configured through `ovmf-path`.
+    Uefi,
+    /// Boot with the firmware at the given path (passed as `-bios <path>`).
+    Custom(PathBuf),
+}
@@ -1,12 +1,16 @@
 use anyhow::{anyhow, Context, Result};
 use bootimage::{
-    args::{BuildArgs, BuildCommand},
+    args::{BuildArgs, BuildCommand, RunnerArgs},
     builder::Builder,
-    config, help,
+    color,
+    config::{self, BootMode},
+    device, help, init, run,
 };
 use std::{
-    env,
+    env, fs,
     path::{Path, PathBuf},
+    process,
+    time::Instant,
 };
 
 pub fn main() -> Result<()> {
@@ -38,19 +42,85 @@ pub fn main() -> Result<()> {
             help::print_cargo_bootimage_help();
             Ok(())
         }
+        BuildCommand::Init(dir) => {
+            for path in init::init(&dir)? {
+                println!("Created {}", path.display());
+            }
+            Ok(())
+        }
     }
 }
 
 fn build(args: BuildArgs) -> Result<()> {
+    if let Some(choice) = args.color() {
+        color::set_color_choice(choice);
+    }
     let mut builder = Builder::new(args.manifest_path().map(PathBuf::from))?;
-    let config = config::read_config(builder.manifest_path())?;
+    builder.set_debug_artifacts(args.debug_artifacts());
+    builder.set_metadata_options(args.metadata_options().to_vec());
+    builder.set_config_overrides(args.config_overrides().to_vec());
+    builder.set_deny_warnings(args.deny_warnings());
+    let mut config = config::read_config_for_profile(builder.manifest_path(), args.profile())?;
+    if args.arch().is_some() {
+        config.arch = args.arch();
+    }
+    if args.reuse_target_artifacts() {
+        config.shared_target_dir = true;
+    }
+    builder.set_metadata_retries(config.metadata_retries);
+    if args.print_config() {
+        print!("{}", config.to_toml());
+        return Ok(());
+    }
+    if args.list_images() {
+        let executables = builder.predict_executables(args.cargo_args(), args.profile())?;
+        for executable in executables {
+            if let (Some(out_dir), Some(bin_name)) = (
+                executable.parent(),
+                executable.file_stem().and_then(|s| s.to_str()),
+            ) {
+                println!("{}", bootimage_path(out_dir, bin_name).display());
+            }
+        }
+        return Ok(());
+    }
     let quiet = args.quiet();
+    let start = Instant::now();
 
-    let executables = builder.build_kernel(&args.cargo_args(), &config, quiet)?;
+    let mut executables = builder.build_kernel(args.cargo_args(), &config, quiet)?;
+    let requested: Vec<&str> = args.bin().into_iter().chain(args.example()).collect();
+    if !requested.is_empty() {
+        // only create disk images for the requested binary and/or example
+        executables.retain(|executable| {
+            requested
+                .iter()
+                .any(|name| executable.file_stem() == Some(name.as_ref()))
+        });
+    }
     if executables.is_empty() {
         return Err(anyhow!("no executables built"));
     }
+    if args.write_to().is_some() && executables.len() > 1 {
+        return Err(anyhow!(
+            "`--write-to` requires a single disk image, use `--bin` or `--example` to select one"
+        ));
+    }
+    let direct_kernel = config.boot_mode == BootMode::DirectKernel;
+    if args.write_to().is_some() && direct_kernel {
+        return Err(anyhow!(
+            "`--write-to` requires a disk image, which is not created with `boot-mode = \"direct-kernel\"`"
+        ));
+    }
+    if args.run() && executables.len() > 1 {
+        return Err(anyhow!(
+            "`--run` requires a single disk image, use `--bin` or `--example` to select one"
+        ));
+    }
 
+    let mut image_count = 0;
+    let mut total_size = 0;
+    let mut run_target = None;
+    let mut artifacts = Vec::new();
     for executable in executables {
         let out_dir = executable
             .parent()
@@ -61,6 +131,20 @@ fn build(args: BuildArgs) -> Result<()> {
             .to_str()
             .ok_or_else(|| anyhow!("executable file stem not valid utf8"))?;
 
+        if direct_kernel {
+            // QEMU boots the kernel executable directly through `-kernel`
+            if !quiet {
+                println!(
+                    "Not creating a bootimage for `{}` (boot-mode is `direct-kernel`)",
+                    bin_name
+                );
+            }
+            if args.run() {
+                run_target = Some((executable.clone(), executable));
+            }
+            continue;
+        }
+
         // We don't have access to a CARGO_MANIFEST_DIR environment variable
         // here because `cargo bootimage` is started directly by the user. We
         // therefore have to find out the path to the Cargo.toml of the
@@ -69,15 +153,34 @@ fn build(args: BuildArgs) -> Result<()> {
         //
         // To retrieve the correct Cargo.toml path, we look for the binary name
         // in the `cargo metadata` output and then get the manifest path from
-        // the corresponding package.
+        // the corresponding package. If `--package` is given, only the selected
+        // packages are considered, since multiple workspace members might have a
+        // binary of the same name.
         let kernel_package = builder
-            .kernel_package_for_bin(bin_name)
-            .context("Failed to run cargo metadata to find out kernel manifest path")?
-            .ok_or_else(|| anyhow!("Failed to find kernel binary in cargo metadata output"))?;
+            .kernel_package_for_bin(bin_name, args.packages())
+            .with_context(|| {
+                format!(
+                    "Failed to run cargo metadata to find out manifest path of binary `{}`",
+                    bin_name
+                )
+            })?
+            .ok_or_else(|| {
+                anyhow!(
+                    "Failed to find kernel binary `{}` in cargo metadata output",
+                    bin_name
+                )
+            })?;
         let kernel_manifest_path = &kernel_package.manifest_path.to_owned();
+        let package_name = kernel_package.name.clone();
 
-        let bootimage_path = out_dir.join(format!("bootimage-{}.bin", bin_name));
-        builder.create_bootimage(kernel_manifest_path, &executable, &bootimage_path, quiet)?;
+        let bootimage_path = bootimage_path(out_dir, bin_name);
+        let bootimage = builder.create_bootimage_detailed(
+            kernel_manifest_path,
+            &executable,
+            &bootimage_path,
+            &config,
+            quiet,
+        )?;
         if !args.quiet() {
             println!(
                 "Created bootimage for `{}` at `{}`",
@@ -85,7 +188,97 @@ fn build(args: BuildArgs) -> Result<()> {
                 bootimage_path.display()
             );
         }
+        image_count += 1;
+        total_size += fs::metadata(&bootimage_path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        if args.manifest_out().is_some() {
+            let mut add = |kind, path: &Path| {
+                artifacts.push(artifact_entry(kind, path, &package_name, bin_name));
+            };
+            add("disk-image", &bootimage.image_path);
+            if let Some(path) = &bootimage.mbr_image_path {
+                add("mbr-image", path);
+            }
+            if let Some(path) = &bootimage.converted_image_path {
+                add("converted-image", path);
+            }
+            for path in &bootimage.additional_image_paths {
+                add("additional-image", path);
+            }
+            if let Some(path) = &bootimage.symbol_path {
+                add("symbol-file", path);
+            }
+            if let Some(path) = &bootimage.stripped_kernel_path {
+                add("stripped-kernel", path);
+            }
+        }
+
+        if let Some(device_path) = args.write_to() {
+            device::write_image(&bootimage_path, device_path, args.force())?;
+            if !args.quiet() {
+                println!(
+                    "Wrote bootimage for `{}` to `{}`",
+                    bin_name,
+                    device_path.display()
+                );
+            }
+        }
+
+        if args.run() {
+            run_target = Some((executable, bootimage_path));
+        }
+    }
+
+    if !quiet && image_count > 0 {
+        println!(
+            "Created {} bootimage{} (total {:.1} MiB) in {:.1}s",
+            image_count,
+            if image_count == 1 { "" } else { "s" },
+            total_size as f64 / (1024.0 * 1024.0),
+            start.elapsed().as_secs_f64()
+        );
+    }
+
+    if let Some(path) = args.manifest_out() {
+        let mut manifest = json::JsonValue::new_object();
+        manifest["artifacts"] = artifacts.into();
+        fs::write(path, manifest.pretty(2)).with_context(|| {
+            format!("failed to write artifact manifest to `{}`", path.display())
+        })?;
+        if !quiet {
+            println!("Wrote artifact manifest to `{}`", path.display());
+        }
+    }
+
+    if let Some((executable, bootimage_path)) = run_target {
+        let runner_args = RunnerArgs::new(executable)
+            .quiet(quiet)
+            .runner_args(args.run_args().to_vec());
+        let exit_code = run::run(config, runner_args, &bootimage_path, false)?;
+        if exit_code != 0 {
+            process::exit(exit_code);
+        }
     }
 
     Ok(())
 }
+
+/// Returns an entry of the `--manifest-out` file for the given artifact.
+fn artifact_entry(kind: &str, path: &Path, package: &str, target: &str) -> json::JsonValue {
+    let size = fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    json::object! {
+        "kind" => kind,
+        "path" => path.display().to_string(),
+        "size" => size,
+        "package" => package,
+        "target" => target,
+    }
+}
+
+/// Returns the path of the disk image that is created for the given binary.
+fn bootimage_path(out_dir: &Path, bin_name: &str) -> PathBuf {
+    out_dir.join(format!("bootimage-{}.bin", bin_name))
+}
@@ -1,7 +1,11 @@
-use super::error::BootloaderError;
+use super::{error::BootloaderError, toolchain};
+use crate::config::Config;
 use cargo_metadata::{Metadata, Package};
 use std::{
+    collections::hash_map::DefaultHasher,
     fs,
+    hash::{Hash, Hasher},
+    io,
     path::{Path, PathBuf},
     process::Command,
 };
@@ -12,9 +16,18 @@ pub struct BuildConfig {
     target: PathBuf,
     features: Vec<String>,
     target_dir: PathBuf,
+    xbuild_sysroot_dir: PathBuf,
+    cache_dir: PathBuf,
     kernel_bin_path: PathBuf,
     kernel_manifest_path: PathBuf,
+    kernel_profile: Option<String>,
     build_std: Option<String>,
+    build_std_features: Option<String>,
+    config_overrides: Vec<String>,
+    cargo: Option<PathBuf>,
+    locked: bool,
+    kernel_env_var: String,
+    kernel_manifest_env_var: String,
 }
 
 impl BuildConfig {
@@ -23,6 +36,7 @@ impl BuildConfig {
         project_metadata: &Metadata,
         kernel_manifest_path: &Path,
         kernel_bin_path: &Path,
+        config: &Config,
     ) -> Result<Self, BootloaderError> {
         let kernel_pkg = project_metadata
             .packages
@@ -32,12 +46,20 @@ impl BuildConfig {
                 manifest_path: kernel_manifest_path.to_owned(),
             })?;
 
-        let bootloader_pkg = bootloader_package(project_metadata, kernel_pkg)?;
-        let bootloader_root = bootloader_pkg.manifest_path.parent().ok_or_else(|| {
+        // An explicitly configured bootloader manifest bypasses the dependency search, which
+        // allows using bootloaders that are not a dependency of the kernel.
+        let (bootloader_manifest_path, bootloader_pkg) = match &config.bootloader_manifest_path {
+            Some(path) => (path.clone(), None),
+            None => {
+                let bootloader_pkg = bootloader_package(project_metadata, kernel_pkg)?;
+                (bootloader_pkg.manifest_path.clone(), Some(bootloader_pkg))
+            }
+        };
+        let bootloader_root = bootloader_manifest_path.parent().ok_or_else(|| {
             BootloaderError::BootloaderInvalid("bootloader manifest has no target directory".into())
         })?;
 
-        let cargo_toml_content = fs::read_to_string(&bootloader_pkg.manifest_path)
+        let cargo_toml_content = fs::read_to_string(&bootloader_manifest_path)
             .map_err(|err| format!("bootloader has no valid Cargo.toml: {}", err))
             .map_err(BootloaderError::BootloaderInvalid)?;
         let cargo_toml = cargo_toml_content
@@ -68,52 +90,142 @@ impl BuildConfig {
                 None
             }
         };
+        // Prefer `-Zbuild-std` over `cargo xbuild` if the active toolchain supports it (unless
+        // disabled through `auto-build-std`).
+        let (build_std, build_std_features) = match build_std {
+            Some(build_std) => (Some(build_std), None),
+            None if config.auto_build_std && toolchain::supports_build_std() => (
+                Some(toolchain::BUILD_STD_CRATES.into()),
+                Some(toolchain::BUILD_STD_FEATURES.into()),
+            ),
+            None => (None, None),
+        };
 
-        let binary_feature = cargo_toml
-            .get("features")
-            .and_then(|f| f.get("binary"))
-            .is_some();
+        let binary_feature = config.bootloader_binary_feature
+            && cargo_toml
+                .get("features")
+                .and_then(|f| f.get("binary"))
+                .is_some();
 
-        let resolve_opt = project_metadata.resolve.as_ref();
-        let resolve = resolve_opt.ok_or(BootloaderError::CargoMetadataIncomplete {
-            key: "resolve".into(),
-        })?;
-        let bootloader_resolve = resolve
-            .nodes
-            .iter()
-            .find(|n| n.id == bootloader_pkg.id)
-            .ok_or(BootloaderError::CargoMetadataIncomplete {
-                key: format!("resolve[\"{}\"]", bootloader_pkg.name),
-            })?;
-        let mut features = bootloader_resolve.features.clone();
+        let (bootloader_name, mut features) = match bootloader_pkg {
+            Some(bootloader_pkg) => (
+                bootloader_pkg.name.clone(),
+                resolved_features(project_metadata, bootloader_pkg)?,
+            ),
+            None => {
+                let name = cargo_toml
+                    .get("package")
+                    .and_then(|p| p.get("name"))
+                    .and_then(|n| n.as_str())
+                    .ok_or_else(|| {
+                        BootloaderError::BootloaderInvalid(
+                            "No `package.name` key found in Cargo.toml of bootloader".into(),
+                        )
+                    })?;
+                (name.to_owned(), Vec::new())
+            }
+        };
         if binary_feature {
             features.push("binary".into());
         }
 
-        let bootloader_name = &bootloader_pkg.name;
-        let target_dir = project_metadata
+        let bootloader_dir = project_metadata
+            .target_directory
+            .join("bootimage")
+            .join(&bootloader_name);
+        let target_dir = if config.shared_target_dir {
+            project_metadata.target_directory.clone()
+        } else {
+            bootloader_dir.clone()
+        };
+        let cache_dir = project_metadata
             .target_directory
             .join("bootimage")
-            .join(bootloader_name);
+            .join("cache");
 
         Ok(BuildConfig {
-            manifest_path: bootloader_pkg.manifest_path.clone(),
             target: bootloader_root.join(target_str),
+            manifest_path: bootloader_manifest_path,
             features,
-            bootloader_name: bootloader_name.clone(),
+            bootloader_name,
             target_dir,
+            xbuild_sysroot_dir: bootloader_dir.join("bootloader-sysroot"),
+            cache_dir,
             kernel_manifest_path: kernel_pkg.manifest_path.clone(),
             kernel_bin_path: kernel_bin_path.to_owned(),
+            kernel_profile: kernel_profile(kernel_bin_path),
             build_std,
+            build_std_features,
+            config_overrides: Vec::new(),
+            cargo: config.bootloader_cargo.clone(),
+            locked: config.bootloader_locked,
+            kernel_env_var: config.kernel_env_var.clone(),
+            kernel_manifest_env_var: config.kernel_manifest_env_var.clone(),
         })
     }
 
+    /// Sets the `--config` arguments that are passed to the bootloader build.
+    pub fn set_config_overrides(&mut self, config_overrides: Vec<String>) {
+        self.config_overrides = config_overrides;
+    }
+
+    /// Returns the path at which the bootloader built from this config is cached.
+    ///
+    /// The path is derived from a hash of the kernel binary, the bootloader manifest path,
+    /// the enabled features, the target, the build mechanism, the `--config` overrides, and
+    /// whether the build is `--locked`.
+    pub fn cache_path(&self) -> io::Result<PathBuf> {
+        let mut hasher = DefaultHasher::new();
+        fs::read(&self.kernel_bin_path)?.hash(&mut hasher);
+        self.manifest_path.hash(&mut hasher);
+        self.features.hash(&mut hasher);
+        self.target.hash(&mut hasher);
+        self.build_std.hash(&mut hasher);
+        self.build_std_features.hash(&mut hasher);
+        self.config_overrides.hash(&mut hasher);
+        self.locked.hash(&mut hasher);
+        self.cargo.hash(&mut hasher);
+        self.kernel_env_var.hash(&mut hasher);
+        self.kernel_manifest_env_var.hash(&mut hasher);
+        self.kernel_profile.hash(&mut hasher);
+        Ok(self.cache_dir.join(format!("{:016x}", hasher.finish())))
+    }
+
+    /// Returns the `arch` field of the bootloader's target JSON, if it can be read.
+    pub fn target_arch(&self) -> Option<String> {
+        let content = fs::read_to_string(&self.target).ok()?;
+        let target = json::parse(&content).ok()?;
+        target["arch"].as_str().map(String::from)
+    }
+
+    /// Returns whether the bootloader is built with `cargo build -Zbuild-std`.
+    pub fn uses_build_std(&self) -> bool {
+        self.build_std.is_some()
+    }
+
+    /// Returns a short description of the mechanism used to build the bootloader.
+    pub fn build_mechanism(&self) -> &'static str {
+        if self.uses_build_std() {
+            "cargo build -Zbuild-std"
+        } else {
+            "cargo xbuild"
+        }
+    }
+
     /// Creates the cargo build command for building the bootloader.
     pub fn build_command(&self) -> Command {
-        let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
+        let cargo = match &self.cargo {
+            Some(cargo) => cargo.clone(),
+            None => std::env::var_os("CARGO")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| "cargo".into()),
+        };
         let mut cmd = Command::new(&cargo);
         if let Some(build_std) = &self.build_std {
-            cmd.arg("build").arg(&format!("-Zbuild-std={}", build_std));
+            cmd.arg("build").arg(format!("-Zbuild-std={}", build_std));
+            if let Some(features) = &self.build_std_features {
+                cmd.arg(format!("-Zbuild-std-features={}", features));
+            }
         } else {
             cmd.arg("xbuild");
         }
@@ -125,17 +237,54 @@ impl BuildConfig {
             .arg(self.features.as_slice().join(" "));
         cmd.arg("--target").arg(&self.target);
         cmd.arg("--release");
-        cmd.env("KERNEL", &self.kernel_bin_path);
-        cmd.env("KERNEL_MANIFEST", &self.kernel_manifest_path);
+        if self.locked {
+            cmd.arg("--locked");
+        }
+        cmd.args(&self.config_overrides);
+        cmd.env(&self.kernel_env_var, &self.kernel_bin_path);
+        cmd.env(&self.kernel_manifest_env_var, &self.kernel_manifest_path);
+        if let Some(profile) = &self.kernel_profile {
+            cmd.env("KERNEL_PROFILE", profile);
+        }
         cmd.env("RUSTFLAGS", "");
-        cmd.env(
-            "XBUILD_SYSROOT_PATH",
-            self.target_dir.join("bootloader-sysroot"),
-        ); // for cargo-xbuild
+        cmd.env("XBUILD_SYSROOT_PATH", &self.xbuild_sysroot_dir); // for cargo-xbuild
         cmd
     }
 }
 
+/// Returns the name of the output directory of the kernel's cargo profile (`debug`,
+/// `release`, or the name of a custom profile).
+///
+/// Returns `None` if the kernel is not in a cargo output directory (e.g. for doctests).
+fn kernel_profile(kernel_bin_path: &Path) -> Option<String> {
+    let name = crate::args::profile_dir(kernel_bin_path)?
+        .file_name()?
+        .to_str()?;
+    if name.starts_with("rustdoctest") {
+        return None;
+    }
+    Some(name.to_owned())
+}
+
+/// Returns the features of the given bootloader package that cargo resolved for the project.
+fn resolved_features(
+    project_metadata: &Metadata,
+    bootloader_pkg: &Package,
+) -> Result<Vec<String>, BootloaderError> {
+    let resolve_opt = project_metadata.resolve.as_ref();
+    let resolve = resolve_opt.ok_or(BootloaderError::CargoMetadataIncomplete {
+        key: "resolve".into(),
+    })?;
+    let bootloader_resolve = resolve
+        .nodes
+        .iter()
+        .find(|n| n.id == bootloader_pkg.id)
+        .ok_or(BootloaderError::CargoMetadataIncomplete {
+            key: format!("resolve[\"{}\"]", bootloader_pkg.name),
+        })?;
+    Ok(bootloader_resolve.features.clone())
+}
+
 /// Returns the package metadata for the bootloader crate
 fn bootloader_package<'a>(
     project_metadata: &'a Metadata,
@@ -157,3 +306,56 @@ fn bootloader_package<'a>(
             key: format!("packages[name = `{}`", &bootloader_name),
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_config() -> BuildConfig {
+        BuildConfig {
+            manifest_path: "bootloader/Cargo.toml".into(),
+            bootloader_name: "bootloader".into(),
+            target: "bootloader/x86_64-bootloader.json".into(),
+            features: Vec::new(),
+            target_dir: "target/bootimage/bootloader".into(),
+            xbuild_sysroot_dir: "target/bootimage/bootloader/bootloader-sysroot".into(),
+            cache_dir: "target/bootimage/cache".into(),
+            kernel_bin_path: "target/x86_64-kernel/debug/kernel".into(),
+            kernel_manifest_path: "Cargo.toml".into(),
+            kernel_profile: Some("debug".into()),
+            build_std: None,
+            build_std_features: None,
+            config_overrides: Vec::new(),
+            cargo: None,
+            locked: false,
+            kernel_env_var: "KERNEL".into(),
+            kernel_manifest_env_var: "KERNEL_MANIFEST".into(),
+        }
+    }
+
+    #[test]
+    fn config_overrides_reach_build_command() {
+        let mut config = build_config();
+        config.set_config_overrides(vec!["--config=net.offline=true".into()]);
+        let command = format!("{:?}", config.build_command());
+        assert!(
+            command.contains("\"--config=net.offline=true\""),
+            "{}",
+            command
+        );
+    }
+
+    #[test]
+    fn cache_path_depends_on_locked() {
+        let kernel_bin_path =
+            std::env::temp_dir().join(format!("bootimage-test-kernel-{}", std::process::id()));
+        fs::write(&kernel_bin_path, "kernel").unwrap();
+        let mut config = build_config();
+        config.kernel_bin_path = kernel_bin_path.clone();
+        let unlocked = config.cache_path().unwrap();
+        config.locked = true;
+        let locked = config.cache_path().unwrap();
+        fs::remove_file(&kernel_bin_path).unwrap();
+        assert_ne!(unlocked, locked);
+    }
+}
@@ -1,20 +1,124 @@
-use super::error::DiskImageError;
-use std::{path::Path, process::Command};
+use super::{elf::ElfFile, error::DiskImageError};
+use crate::config::Arch;
+use std::{
+    convert::TryInto,
+    ffi::OsStr,
+    fs,
+    io::{self, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, Instant},
+};
 
+const BLOCK_SIZE: u64 = 512;
+
+/// Returns the path to the `llvm-objcopy` executable of the `llvm-tools-preview` component.
+fn llvm_objcopy() -> Result<PathBuf, DiskImageError> {
+    let llvm_tools = llvm_tools::LlvmTools::new()?;
+    llvm_tools
+        .tool(&llvm_tools::exe("llvm-objcopy"))
+        .ok_or(DiskImageError::LlvmObjcopyNotFound)
+}
+
+/// Converts the given raw disk image to the given `qemu-img` output format (e.g. `vpc`).
+pub fn convert_image(
+    image_path: &Path,
+    output_path: &Path,
+    format: &str,
+) -> Result<(), DiskImageError> {
+    let mut cmd = Command::new("qemu-img");
+    cmd.arg("convert").arg("-f").arg("raw");
+    cmd.arg("-O").arg(format);
+    cmd.arg(image_path).arg(output_path);
+    let output = cmd.output().map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => DiskImageError::QemuImgNotFound,
+        _ => DiskImageError::Io {
+            message: "failed to execute qemu-img",
+            error: err,
+        },
+    })?;
+    if !output.status.success() {
+        return Err(DiskImageError::QemuImgFailed {
+            stderr: output.stderr,
+        });
+    }
+    Ok(())
+}
+
+/// Writes the debug symbols of the given kernel to `symbol_path` and a copy of the kernel
+/// without debug symbols to `stripped_path`.
+pub fn split_debug_symbols(
+    kernel_bin_path: &Path,
+    symbol_path: &Path,
+    stripped_path: &Path,
+) -> Result<(), DiskImageError> {
+    let objcopy = llvm_objcopy()?;
+    let run = |args: &[&OsStr]| -> Result<(), DiskImageError> {
+        let output =
+            Command::new(&objcopy)
+                .args(args)
+                .output()
+                .map_err(|err| DiskImageError::Io {
+                    message: "failed to execute llvm-objcopy command",
+                    error: err,
+                })?;
+        if !output.status.success() {
+            return Err(DiskImageError::ObjcopyFailed {
+                stderr: output.stderr,
+            });
+        }
+        Ok(())
+    };
+    run(&[
+        "--only-keep-debug".as_ref(),
+        kernel_bin_path.as_os_str(),
+        symbol_path.as_os_str(),
+    ])?;
+    run(&[
+        "--strip-debug".as_ref(),
+        kernel_bin_path.as_os_str(),
+        stripped_path.as_os_str(),
+    ])
+}
+
+/// Converts the bootloader executable to a raw disk image.
+///
+/// The bootloader is placed at `start_offset`, which must be a multiple of the block size.
+/// The space in front of it is filled with zeros. The architecture determines the ELF
+/// input format of `llvm-objcopy`.
+///
+/// The converted bootloader must be larger than a boot sector. If `check_boot_signature`
+/// is set, it must also end its first sector with the BIOS boot signature.
 pub fn create_disk_image(
     bootloader_elf_path: &Path,
     output_bin_path: &Path,
+    arch: Arch,
+    start_offset: u64,
+    check_boot_signature: bool,
+    quiet: bool,
 ) -> Result<(), DiskImageError> {
-    let llvm_tools = llvm_tools::LlvmTools::new()?;
-    let objcopy = llvm_tools
-        .tool(&llvm_tools::exe("llvm-objcopy"))
-        .ok_or(DiskImageError::LlvmObjcopyNotFound)?;
+    if !start_offset.is_multiple_of(BLOCK_SIZE) {
+        return Err(DiskImageError::UnalignedStartOffset {
+            offset: start_offset,
+            block_size: BLOCK_SIZE,
+        });
+    }
+    let objcopy = llvm_objcopy()?;
 
     // convert bootloader to binary
+    if !quiet {
+        print!("Converting bootloader to a disk image... ");
+        let _ = io::stdout().flush();
+    }
+    let (input_format, binary_architecture) = match arch {
+        Arch::X86_64 => ("elf64-x86-64", "i386:x86-64"),
+        Arch::Aarch64 => ("elf64-littleaarch64", "aarch64"),
+        Arch::Riscv64 => ("elf64-littleriscv", "riscv:rv64"),
+    };
     let mut cmd = Command::new(objcopy);
-    cmd.arg("-I").arg("elf64-x86-64");
+    cmd.arg("-I").arg(input_format);
     cmd.arg("-O").arg("binary");
-    cmd.arg("--binary-architecture=i386:x86-64");
+    cmd.arg(format!("--binary-architecture={}", binary_architecture));
     cmd.arg(bootloader_elf_path);
     cmd.arg(output_bin_path);
     let output = cmd.output().map_err(|err| DiskImageError::Io {
@@ -22,21 +126,278 @@ pub fn create_disk_image(
         error: err,
     })?;
     if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("No space left on device") || stderr.contains("ENOSPC") {
+            return Err(DiskImageError::DiskFull {
+                path: output_bin_path.to_owned(),
+            });
+        }
         return Err(DiskImageError::ObjcopyFailed {
             stderr: output.stderr,
         });
     }
+    if !quiet {
+        let size = fs::metadata(output_bin_path).map(|m| m.len()).unwrap_or(0);
+        println!("done ({:.1} MB)", size as f64 / (1 << 20) as f64);
+    }
+    check_bootable(output_bin_path, check_boot_signature)?;
 
+    if start_offset > 0 {
+        prepend_zeros(output_bin_path, start_offset)?;
+    }
     pad_to_nearest_block_size(output_bin_path)?;
     Ok(())
 }
 
+/// Checks that the converted bootloader is larger than a boot sector and (optionally) that
+/// its first sector ends with the boot signature.
+///
+/// Catches bootloader executables without loadable segments, for which `llvm-objcopy`
+/// succeeds but creates an empty or tiny image.
+fn check_bootable(path: &Path, check_boot_signature: bool) -> Result<(), DiskImageError> {
+    let mut boot_sector = Vec::new();
+    fs::File::open(path)
+        .and_then(|file| file.take(BLOCK_SIZE + 1).read_to_end(&mut boot_sector))
+        .map_err(|err| DiskImageError::Io {
+            message: "failed to read boot image",
+            error: err,
+        })?;
+    if boot_sector.len() as u64 <= BLOCK_SIZE {
+        return Err(DiskImageError::ImageTooSmall {
+            size: boot_sector.len() as u64,
+        });
+    }
+    if check_boot_signature && boot_sector[510..512] != [0x55, 0xaa] {
+        return Err(DiskImageError::MissingBootSignature);
+    }
+    Ok(())
+}
+
+/// Moves the content of the given file back by `count` bytes and fills the gap with zeros.
+fn prepend_zeros(path: &Path, count: u64) -> Result<(), DiskImageError> {
+    let data = fs::read(path).map_err(|err| DiskImageError::Io {
+        message: "failed to read boot image",
+        error: err,
+    })?;
+    let write_image = || -> std::io::Result<()> {
+        let mut output = fs::File::create(path)?;
+        io::copy(&mut io::repeat(0).take(count), &mut output)?;
+        output.write_all(&data)?;
+        output.flush()
+    };
+    write_image().map_err(write_error("failed to write boot image", path))
+}
+
+/// Creates a disk image in the format expected by old bootloader versions.
+///
+/// Old bootloaders don't embed the kernel themselves. Instead, the disk image consists of
+/// the `.bootloader` section of the bootloader executable, followed by a 512-byte kernel
+/// info block and the kernel ELF file.
+pub fn create_legacy_disk_image(
+    bootloader_elf_path: &Path,
+    kernel_bin_path: &Path,
+    output_bin_path: &Path,
+) -> Result<(), DiskImageError> {
+    let bootloader_elf = fs::read(bootloader_elf_path).map_err(|err| DiskImageError::Io {
+        message: "failed to read bootloader executable",
+        error: err,
+    })?;
+    let invalid_elf = |message: &str| DiskImageError::InvalidElf {
+        path: bootloader_elf_path.to_owned(),
+        message: message.to_owned(),
+    };
+    let bootloader_data = ElfFile::new(&bootloader_elf)
+        .and_then(|elf| elf.section_data(".bootloader"))
+        .map_err(invalid_elf)?
+        .ok_or(DiskImageError::BootloaderSectionNotFound)?;
+
+    let kernel = fs::read(kernel_bin_path).map_err(|err| DiskImageError::Io {
+        message: "failed to read kernel executable",
+        error: err,
+    })?;
+    let kernel_info_block = create_kernel_info_block(kernel.len() as u64)?;
+
+    let write_image = || -> std::io::Result<()> {
+        let mut output = fs::File::create(output_bin_path)?;
+        output.write_all(bootloader_data)?;
+        output.write_all(&kernel_info_block)?;
+        output.write_all(&kernel)?;
+        output.flush()
+    };
+    write_image().map_err(write_error("failed to write boot image", output_bin_path))?;
+
+    pad_to_nearest_block_size(output_bin_path)?;
+    Ok(())
+}
+
+/// Copies the given disk image to `output_path` and pads the copy.
+///
+/// The copy is padded to at least `min_size` bytes and to a multiple of `block_size`.
+pub fn create_additional_image(
+    image_path: &Path,
+    output_path: &Path,
+    min_size: u64,
+    block_size: u64,
+    quiet: bool,
+) -> Result<(), DiskImageError> {
+    let copy_image = || -> std::io::Result<u64> {
+        let mut image = fs::File::open(image_path)?;
+        let total = image.metadata()?.len();
+        let mut output = fs::File::create(output_path)?;
+        copy_with_progress(&mut image, &mut output, total, "Copying boot image", quiet)
+    };
+    let image_size = copy_image().map_err(write_error("failed to copy boot image", output_path))?;
+    let size = image_size.max(min_size);
+    let padded_size = size.div_ceil(block_size) * block_size;
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(output_path)
+        .map_err(|err| DiskImageError::Io {
+            message: "failed to open additional image",
+            error: err,
+        })?;
+    file.set_len(padded_size)
+        .map_err(write_error("failed to pad additional image", output_path))
+}
+
+/// Wraps the given disk image in a single partition of an MBR partitioned image.
+///
+/// The partition starts at `start_lba` (in 512-byte sectors) and is marked as bootable.
+pub fn create_mbr_image(
+    image_path: &Path,
+    output_path: &Path,
+    start_lba: u32,
+    quiet: bool,
+) -> Result<(), DiskImageError> {
+    if start_lba == 0 {
+        return Err(DiskImageError::PartitionStartLbaZero);
+    }
+    let image = fs::read(image_path).map_err(|err| DiskImageError::Io {
+        message: "failed to read boot image",
+        error: err,
+    })?;
+    let sector_count = (image.len() as u64).div_ceil(BLOCK_SIZE);
+    let too_big = || DiskImageError::ImageTooBigForMbr {
+        size: image.len() as u64,
+    };
+    let sector_count: u32 = sector_count.try_into().map_err(|_| too_big())?;
+    start_lba.checked_add(sector_count).ok_or_else(too_big)?;
+
+    let mbr = create_mbr(start_lba, sector_count);
+    let write_image = || -> std::io::Result<()> {
+        let mut output = fs::File::create(output_path)?;
+        output.write_all(&mbr)?;
+        let gap = u64::from(start_lba - 1) * BLOCK_SIZE;
+        io::copy(&mut io::repeat(0).take(gap), &mut output)?;
+        let total = image.len() as u64;
+        copy_with_progress(
+            &mut &image[..],
+            &mut output,
+            total,
+            "Writing MBR image",
+            quiet,
+        )?;
+        output.flush()
+    };
+    write_image().map_err(write_error("failed to write MBR image", output_path))?;
+
+    pad_to_nearest_block_size(output_path)?;
+    Ok(())
+}
+
+/// Creates a master boot record with a single bootable partition.
+fn create_mbr(start_lba: u32, sector_count: u32) -> [u8; BLOCK_SIZE as usize] {
+    /// The partition type for data without a file system
+    const PARTITION_TYPE: u8 = 0xda;
+
+    let mut mbr = [0u8; BLOCK_SIZE as usize];
+    let entry = &mut mbr[446..462];
+    entry[0] = 0x80; // bootable
+    entry[1..4].copy_from_slice(&chs(start_lba));
+    entry[4] = PARTITION_TYPE;
+    entry[5..8].copy_from_slice(&chs(start_lba + sector_count - 1));
+    entry[8..12].copy_from_slice(&start_lba.to_le_bytes());
+    entry[12..16].copy_from_slice(&sector_count.to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xaa;
+    mbr
+}
+
+/// Converts the given LBA to a CHS address (255 heads, 63 sectors per track).
+///
+/// Addresses that are not representable in CHS are saturated to the maximum address.
+fn chs(lba: u32) -> [u8; 3] {
+    const HEADS: u32 = 255;
+    const SECTORS: u32 = 63;
+
+    let cylinder = lba / (HEADS * SECTORS);
+    if cylinder > 1023 {
+        return [0xfe, 0xff, 0xff];
+    }
+    let head = (lba / SECTORS) % HEADS;
+    let sector = lba % SECTORS + 1;
+    [
+        head as u8,
+        (sector as u8) | ((cylinder >> 2) as u8 & 0xc0),
+        cylinder as u8,
+    ]
+}
+
+/// Copies `reader` to `writer` and returns the number of copied bytes.
+///
+/// If not quiet and stdout is a terminal, the progress (relative to `total` bytes) is
+/// printed at regular intervals.
+fn copy_with_progress(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    total: u64,
+    label: &str,
+    quiet: bool,
+) -> io::Result<u64> {
+    const UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+    let show_progress = !quiet && io::stdout().is_terminal();
+    let mut buffer = vec![0; 1 << 20];
+    let mut copied = 0;
+    let mut last_update = Instant::now();
+    let mut printed = false;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        writer.write_all(&buffer[..read])?;
+        copied += read as u64;
+        if show_progress && last_update.elapsed() >= UPDATE_INTERVAL {
+            print!("\r{}: {}%", label, copied * 100 / total.max(1));
+            io::stdout().flush()?;
+            last_update = Instant::now();
+            printed = true;
+        }
+    }
+    if printed {
+        println!("\r{}: 100%", label);
+    }
+    Ok(copied)
+}
+
+/// Creates the kernel info block that old bootloaders read after the bootloader section.
+fn create_kernel_info_block(kernel_size: u64) -> Result<[u8; BLOCK_SIZE as usize], DiskImageError> {
+    let kernel_size: u32 = kernel_size
+        .try_into()
+        .map_err(|_| DiskImageError::KernelTooBig { size: kernel_size })?;
+    let mut kernel_info_block = [0u8; BLOCK_SIZE as usize];
+    kernel_info_block[0..4].copy_from_slice(&kernel_size.to_le_bytes());
+    Ok(kernel_info_block)
+}
+
 fn pad_to_nearest_block_size(output_bin_path: &Path) -> Result<(), DiskImageError> {
-    const BLOCK_SIZE: u64 = 512;
     use std::fs::OpenOptions;
     let file = OpenOptions::new()
         .write(true)
-        .open(&output_bin_path)
+        .open(output_bin_path)
         .map_err(|err| DiskImageError::Io {
             message: "failed to open boot image",
             error: err,
@@ -54,9 +415,61 @@ fn pad_to_nearest_block_size(output_bin_path: &Path) -> Result<(), DiskImageErro
     } else {
         0
     };
-    file.set_len(file_size + padding)
-        .map_err(|err| DiskImageError::Io {
-            message: "failed to pad boot image to a multiple of the block size",
-            error: err,
-        })
+    file.set_len(file_size + padding).map_err(write_error(
+        "failed to pad boot image to a multiple of the block size",
+        output_bin_path,
+    ))
+}
+
+/// Creates an error for a failed write to the boot image, detecting full disks.
+fn write_error<'a>(
+    message: &'static str,
+    output_bin_path: &'a Path,
+) -> impl FnOnce(io::Error) -> DiskImageError + 'a {
+    move |err| {
+        if err.kind() == io::ErrorKind::StorageFull {
+            DiskImageError::DiskFull {
+                path: output_bin_path.to_owned(),
+            }
+        } else {
+            DiskImageError::Io {
+                message,
+                error: err,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mbr() {
+        let mbr = create_mbr(2048, 100);
+        assert_eq!(mbr[510..], [0x55, 0xaa]);
+        let entry = &mbr[446..462];
+        assert_eq!(entry[0], 0x80);
+        assert_eq!(entry[1..4], chs(2048));
+        assert_eq!(entry[5..8], chs(2147));
+        assert_eq!(entry[8..12], 2048u32.to_le_bytes());
+        assert_eq!(entry[12..16], 100u32.to_le_bytes());
+        assert!(mbr[..446].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn chs_addresses() {
+        assert_eq!(chs(0), [0, 1, 0]);
+        assert_eq!(chs(62), [0, 63, 0]);
+        assert_eq!(chs(63), [1, 1, 0]);
+        assert_eq!(chs(2048), [32, 33, 0]);
+        assert_eq!(chs(255 * 63), [0, 1, 1]);
+        assert_eq!(chs(1024 * 255 * 63), [0xfe, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn mbr_image_rejects_start_lba_zero() {
+        let result = create_mbr_image(Path::new("image.bin"), Path::new("mbr.img"), 0, true);
+        assert!(matches!(result, Err(DiskImageError::PartitionStartLbaZero)));
+    }
 }
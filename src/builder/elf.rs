@@ -0,0 +1,188 @@
+use std::convert::TryInto;
+
+/// A minimal reader for little-endian ELF64 files.
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ElfFile<'a> {
+    /// Checks the ELF header of the given data.
+    pub fn new(data: &'a [u8]) -> Result<Self, &'static str> {
+        if data.len() < 64 || &data[0..4] != b"\x7fELF" {
+            return Err("not an ELF file");
+        }
+        if data[4] != 2 {
+            return Err("not a 64-bit ELF file");
+        }
+        if data[5] != 1 {
+            return Err("not a little-endian ELF file");
+        }
+        Ok(ElfFile { data })
+    }
+
+    /// Returns the `e_machine` field of the ELF header (e.g. `62` for x86_64).
+    pub fn machine(&self) -> Result<u16, &'static str> {
+        self.read_u16(0x12)
+    }
+
+    /// Returns whether the file has a `PT_INTERP` program header.
+    ///
+    /// This is the case for dynamically linked executables of hosted targets (e.g. Linux),
+    /// which request a program interpreter.
+    pub fn has_interpreter(&self) -> Result<bool, &'static str> {
+        const PT_INTERP: u32 = 3;
+
+        let program_header_offset = self.read_offset(0x20)?;
+        let program_header_size = usize::from(self.read_u16(0x36)?);
+        let program_header_count = usize::from(self.read_u16(0x38)?);
+        for index in 0..program_header_count {
+            let header = table_entry(program_header_offset, index, program_header_size)?;
+            if self.read_u32(header)? == PT_INTERP {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns the raw data of the section with the given name.
+    pub fn section_data(&self, name: &str) -> Result<Option<&'a [u8]>, &'static str> {
+        let section_header_offset = self.read_offset(0x28)?;
+        let section_header_size = usize::from(self.read_u16(0x3a)?);
+        let section_count = usize::from(self.read_u16(0x3c)?);
+        let names_index = usize::from(self.read_u16(0x3e)?);
+
+        let header = |index: usize| table_entry(section_header_offset, index, section_header_size);
+        let names_offset = self.read_offset(field(header(names_index)?, 24)?)?;
+
+        for index in 0..section_count {
+            let name_offset = field(names_offset, self.read_u32(header(index)?)? as usize)?;
+            let section_name = self
+                .data
+                .get(name_offset..)
+                .and_then(|bytes| bytes.split(|&b| b == 0).next())
+                .ok_or("section name out of bounds")?;
+            if section_name == name.as_bytes() {
+                let offset = self.read_offset(field(header(index)?, 24)?)?;
+                let size = self.read_offset(field(header(index)?, 32)?)?;
+                let data = field(offset, size)
+                    .ok()
+                    .and_then(|end| self.data.get(offset..end))
+                    .ok_or("section data out of bounds")?;
+                return Ok(Some(data));
+            }
+        }
+        Ok(None)
+    }
+
+    fn bytes<const N: usize>(&self, offset: usize) -> Result<[u8; N], &'static str> {
+        self.data
+            .get(offset..field(offset, N)?)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or("unexpected end of ELF file")
+    }
+
+    fn read_u16(&self, offset: usize) -> Result<u16, &'static str> {
+        self.bytes(offset).map(u16::from_le_bytes)
+    }
+
+    fn read_u32(&self, offset: usize) -> Result<u32, &'static str> {
+        self.bytes(offset).map(u32::from_le_bytes)
+    }
+
+    fn read_u64(&self, offset: usize) -> Result<u64, &'static str> {
+        self.bytes(offset).map(u64::from_le_bytes)
+    }
+
+    /// Reads a 64-bit file offset or size.
+    fn read_offset(&self, offset: usize) -> Result<usize, &'static str> {
+        self.read_u64(offset)?
+            .try_into()
+            .map_err(|_| "offset out of range")
+    }
+}
+
+/// Returns the offset of the field at `offset` in the structure at `base`.
+fn field(base: usize, offset: usize) -> Result<usize, &'static str> {
+    base.checked_add(offset).ok_or("offset out of range")
+}
+
+/// Returns the offset of the entry with the given index in a table of `entry_size` entries.
+fn table_entry(
+    table_offset: usize,
+    index: usize,
+    entry_size: usize,
+) -> Result<usize, &'static str> {
+    index
+        .checked_mul(entry_size)
+        .and_then(|offset| table_offset.checked_add(offset))
+        .ok_or("offset out of range")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an ELF64 header for x86_64 without program or section headers.
+    fn header() -> Vec<u8> {
+        let mut data = vec![0; 64];
+        data[0..4].copy_from_slice(b"\x7fELF");
+        data[4] = 2;
+        data[5] = 1;
+        data[0x12..0x14].copy_from_slice(&62u16.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn truncated_header() {
+        let data = header();
+        assert!(ElfFile::new(&data[..63]).is_err());
+        assert!(ElfFile::new(&data).is_ok());
+    }
+
+    #[test]
+    fn program_headers() {
+        let mut data = header();
+        data[0x20..0x28].copy_from_slice(&64u64.to_le_bytes());
+        data[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+        data[0x38..0x3a].copy_from_slice(&1u16.to_le_bytes());
+        assert!(ElfFile::new(&data).unwrap().has_interpreter().is_err());
+
+        data.resize(64 + 56, 0);
+        data[64..68].copy_from_slice(&3u32.to_le_bytes());
+        assert_eq!(ElfFile::new(&data).unwrap().has_interpreter(), Ok(true));
+    }
+
+    #[test]
+    fn overflowing_offsets() {
+        let mut data = header();
+        data[0x20..0x28].copy_from_slice(&u64::MAX.to_le_bytes());
+        data[0x36..0x38].copy_from_slice(&u16::MAX.to_le_bytes());
+        data[0x38..0x3a].copy_from_slice(&u16::MAX.to_le_bytes());
+        data[0x28..0x30].copy_from_slice(&u64::MAX.to_le_bytes());
+        data[0x3a..0x3c].copy_from_slice(&u16::MAX.to_le_bytes());
+        data[0x3c..0x3e].copy_from_slice(&u16::MAX.to_le_bytes());
+        data[0x3e..0x40].copy_from_slice(&u16::MAX.to_le_bytes());
+        let elf = ElfFile::new(&data).unwrap();
+        assert!(elf.has_interpreter().is_err());
+        assert!(elf.section_data(".bootloader").is_err());
+    }
+
+    #[test]
+    fn overflowing_section_data() {
+        let mut data = header();
+        // a single section `.a` at offset 128 that is also the section name table
+        data[0x28..0x30].copy_from_slice(&64u64.to_le_bytes());
+        data[0x3a..0x3c].copy_from_slice(&64u16.to_le_bytes());
+        data[0x3c..0x3e].copy_from_slice(&1u16.to_le_bytes());
+        data.resize(128, 0);
+        data[64 + 24..64 + 32].copy_from_slice(&128u64.to_le_bytes());
+        data[64 + 32..64 + 40].copy_from_slice(&3u64.to_le_bytes());
+        data.extend_from_slice(b".a\0");
+        let elf = ElfFile::new(&data).unwrap();
+        assert_eq!(elf.section_data(".a"), Ok(Some(&b".a\0"[..])));
+
+        data[64 + 32..64 + 40].copy_from_slice(&u64::MAX.to_le_bytes());
+        let elf = ElfFile::new(&data).unwrap();
+        assert!(elf.section_data(".a").is_err());
+    }
+}
@@ -29,13 +29,71 @@ pub enum BuildKernelError {
     )]
     XbuildNotFound,
 
+    /// The kernel is built with `-Zbuild-std`, but the `rust-src` component is not installed
+    #[error(
+        "The `rust-src` component is required for `-Zbuild-std`, but it is not installed.\n\
+    Run `rustup component add rust-src` to install it."
+    )]
+    RustSrcNotInstalled,
+
+    /// The kernel build uses `-Z` flags (e.g. `-Zbuild-std`), but the active toolchain is not
+    /// a nightly toolchain
+    #[error(
+        "The kernel build uses unstable `-Z` flags (e.g. `-Zbuild-std`), which require a \
+    nightly Rust toolchain, but the active toolchain is `{release}`.\n\
+    Run `rustup toolchain install nightly --component rust-src` and select it, e.g. through \
+    a `rust-toolchain.toml` file with `channel = \"nightly\"` or `rustup override set nightly`."
+    )]
+    NightlyRequired {
+        /// The `release` reported by `rustc -vV` (e.g. `1.80.0`)
+        release: String,
+    },
+
+    /// The configured `kernel-linker-script` does not exist
+    #[error("The kernel linker script `{}` does not exist", path.display())]
+    LinkerScriptNotFound {
+        /// The configured linker script path
+        path: PathBuf,
+    },
+
+    /// The `pre-build-command` is empty
+    #[error("The `pre-build-command` must not be empty")]
+    PreBuildCommandEmpty,
+
+    /// The `pre-build-command` exited with an error
+    #[error(
+        "The pre-build command `{command}` failed.\nStderr: {}",
+        String::from_utf8_lossy(.stderr)
+    )]
+    PreBuildCommandFailed {
+        /// The executed command
+        command: String,
+        /// The output of the command to standard error
+        stderr: Vec<u8>,
+    },
+
     /// Running `cargo build` failed.
-    #[error("Kernel build failed.\nStderr: {}", String::from_utf8_lossy(.stderr))]
+    #[error(
+        "Kernel build failed.\nStderr: {}{}",
+        String::from_utf8_lossy(.stderr),
+        linker_failure_note(.stderr)
+    )]
     BuildFailed {
         /// The standard error output.
         stderr: Vec<u8>,
     },
 
+    /// The kernel build emitted warnings, but warnings were denied
+    #[error(
+        "Kernel build emitted {} warning(s), which are denied:\n\n{}",
+        warnings.len(),
+        warnings.concat()
+    )]
+    WarningsDenied {
+        /// The rendered warnings
+        warnings: Vec<String>,
+    },
+
     /// The output of `cargo build --message-format=json` was not valid UTF-8
     #[error("Output of kernel build with --message-format=json is not valid UTF-8:\n{0}")]
     BuildJsonOutputInvalidUtf8(std::string::FromUtf8Error),
@@ -56,17 +114,70 @@ pub enum CreateBootimageError {
     #[error("Error while running `cargo metadata` for current project: {0:?}")]
     CargoMetadata(#[from] cargo_metadata::Error),
 
+    /// The bootloader is built with `-Zbuild-std`, but the `rust-src` component is not
+    /// installed
+    #[error(
+        "The `rust-src` component is required for `-Zbuild-std`, but it is not installed.\n\
+    Run `rustup component add rust-src` to install it."
+    )]
+    RustSrcNotInstalled,
+
     /// Building the bootloader failed
-    #[error("Bootloader build failed.\nStderr: {}", String::from_utf8_lossy(.stderr))]
+    #[error(
+        "Bootloader build failed.\nStderr: {}{}",
+        String::from_utf8_lossy(.stderr),
+        linker_failure_note(.stderr)
+    )]
     BootloaderBuildFailed {
         /// The `cargo build` output to standard error
         stderr: Vec<u8>,
     },
 
+    /// The bootloader build emitted warnings, but warnings were denied
+    #[error(
+        "Bootloader build emitted {} warning(s), which are denied:\n\n{}",
+        warnings.len(),
+        warnings.concat()
+    )]
+    BootloaderWarningsDenied {
+        /// The rendered warnings
+        warnings: Vec<String>,
+    },
+
     /// Disk image creation failed
     #[error("An error occurred while trying to create the disk image: {0}")]
     DiskImage(#[from] DiskImageError),
 
+    /// The `post-image-command` is empty
+    #[error("The `post-image-command` must not be empty")]
+    PostImageCommandEmpty,
+
+    /// The kernel executable was not built for a bare-metal target matching the bootloader
+    #[error(
+        "The kernel executable `{}` was not built for the bootloader target: {reason}.\n\n\
+        Make sure to build the kernel for a bare-metal target, e.g. by passing \
+        `--target <your-target>.json` or by setting `build.target` in `.cargo/config`.",
+        path.display()
+    )]
+    WrongKernelTarget {
+        /// The path to the kernel executable
+        path: PathBuf,
+        /// Why the kernel does not match the bootloader target
+        reason: String,
+    },
+
+    /// The `post-image-command` exited with an error
+    #[error(
+        "The post-image command `{command}` failed.\nStderr: {}",
+        String::from_utf8_lossy(.stderr)
+    )]
+    PostImageCommandFailed {
+        /// The executed command
+        command: String,
+        /// The output of the command to standard error
+        stderr: Vec<u8>,
+    },
+
     /// An unexpected I/O error occurred
     #[error("I/O error: {message}:\n{error}")]
     Io {
@@ -84,6 +195,21 @@ pub enum CreateBootimageError {
     BuildJsonOutputInvalidJson(json::Error),
 }
 
+/// Returns an explanatory note if the given build output contains a `rust-lld` link failure.
+///
+/// Returns an empty string otherwise.
+fn linker_failure_note(stderr: &[u8]) -> &'static str {
+    if String::from_utf8_lossy(stderr).contains("linking with `rust-lld` failed") {
+        "\n\nNote: Linking with `rust-lld` failed. This is usually caused by a linker script \
+        or target specification that doesn't match the kernel (e.g. a wrong `pre-link-args` or \
+        `kernel-linker-script`) or by a missing `llvm-tools-preview` rustup component \
+        (`rustup component add llvm-tools-preview`). See \
+        https://os.phil-opp.com/minimal-rust-kernel/ for a working target specification."
+    } else {
+        ""
+    }
+}
+
 /// There is something wrong with the bootloader dependency.
 #[derive(Debug, Error)]
 pub enum BootloaderError {
@@ -140,6 +266,99 @@ pub enum DiskImageError {
         stderr: Vec<u8>,
     },
 
+    /// The disk ran out of space while writing the boot image
+    #[error(
+        "Disk full while writing boot image `{}`.\n\n\
+        Free up some space on the partition that contains the target directory.",
+        path.display()
+    )]
+    DiskFull {
+        /// The path of the boot image
+        path: PathBuf,
+    },
+
+    /// An executable could not be parsed as ELF file
+    #[error("Failed to parse `{}` as ELF file: {message}", path.display())]
+    InvalidElf {
+        /// The path of the executable
+        path: PathBuf,
+        /// Description of the problem
+        message: String,
+    },
+
+    /// The bootloader has no `.bootloader` section (required for `legacy-image`)
+    #[error(
+        "The bootloader executable has no `.bootloader` section.\n\n\
+        The `legacy-image` option only works with old bootloader versions that provide \
+        this section."
+    )]
+    BootloaderSectionNotFound,
+
+    /// The disk image is too big to be described by an MBR partition entry
+    #[error("The disk image ({size} bytes) is too big for an MBR partition table")]
+    ImageTooBigForMbr {
+        /// The size of the disk image in bytes
+        size: u64,
+    },
+
+    /// The MBR partition is configured to start at LBA 0, which holds the MBR itself
+    #[error("The partition-start-lba must not be 0 because the first block holds the MBR")]
+    PartitionStartLbaZero,
+
+    /// The configured `image-start-offset` is not a multiple of the block size
+    #[error(
+        "The image-start-offset {offset} is not a multiple of the block size ({block_size} bytes)"
+    )]
+    UnalignedStartOffset {
+        /// The configured start offset
+        offset: u64,
+        /// The block size of the disk image
+        block_size: u64,
+    },
+
+    /// The converted bootloader is not larger than a boot sector
+    #[error(
+        "The disk image is only {size} bytes large, so the bootloader executable probably \
+        has no loadable segments.\n\n\
+        This indicates a problem with the bootloader build (e.g. a wrong linker script or \
+        target specification)."
+    )]
+    ImageTooSmall {
+        /// The size of the converted bootloader in bytes
+        size: u64,
+    },
+
+    /// The first sector of the converted bootloader has no boot signature
+    #[error(
+        "The disk image has no boot signature (0x55 0xAA at offset 510), so it is not \
+        bootable through the BIOS.\n\n\
+        This indicates a problem with the bootloader build. For bootloaders that don't boot \
+        through the BIOS, set `check-boot-signature = false`."
+    )]
+    MissingBootSignature,
+
+    /// The `qemu-img` executable that is required for `image-type` was not found
+    #[error(
+        "Could not find `qemu-img`, which is required for converting the disk image to \
+        another `image-type`.\n\n\
+        It is part of the QEMU tools (e.g. the `qemu-utils` package on Debian/Ubuntu)."
+    )]
+    QemuImgNotFound,
+
+    /// The `qemu-img convert` command failed
+    #[error("Failed to convert the disk image with `qemu-img`: {}", String::from_utf8_lossy(.stderr))]
+    QemuImgFailed {
+        /// The output of `qemu-img` to standard error
+        stderr: Vec<u8>,
+    },
+
+    /// The kernel is too big to be loaded by the legacy BIOS bootloader
+    #[error("The kernel is too big to be loaded by the bootloader ({size} bytes)")]
+    KernelTooBig {
+        /// The size of the kernel executable in bytes
+        size: u64,
+    },
+
     /// An unexpected I/O error occurred
     #[error("I/O error: {message}:\n{error}")]
     Io {
@@ -1,26 +1,44 @@
 //! Provides functions to build the kernel and the bootloader.
 
-use crate::config::Config;
+use crate::{
+    color::{self, Color, Stream},
+    config::{Arch, Config, ImageType, PartitionTable},
+};
 use cargo_metadata::Metadata;
 use error::{BootloaderError, BuildKernelError, BuilderError, CreateBootimageError};
 use std::{
+    fs,
+    io::{self, BufRead, Read},
     path::{Path, PathBuf},
-    process,
+    process, thread,
+    time::Duration,
 };
 
 /// Provides the build command for the bootloader.
 mod bootloader;
 /// Provides a function to create the bootable disk image.
 mod disk_image;
+/// Provides a minimal ELF file reader.
+mod elf;
 /// Contains the errors types returned by the `Builder` methods.
 pub mod error;
+/// Provides functions to query the capabilities of the active toolchain.
+mod toolchain;
 
 /// Allows building the kernel and creating a bootable disk image with it.
 pub struct Builder {
     manifest_path: PathBuf,
     project_metadata: Option<Metadata>,
+    metadata_options: Vec<String>,
+    config_overrides: Vec<String>,
+    debug_artifacts: bool,
+    metadata_retries: u32,
+    deny_warnings: bool,
 }
 
+/// The time to wait before retrying a `cargo metadata` invocation that failed transiently.
+const METADATA_RETRY_BACKOFF: Duration = Duration::from_secs(2);
+
 impl Builder {
     /// Creates a new builder for the project at the given manifest path
     ///
@@ -33,7 +51,10 @@ impl Builder {
         }) {
             Some(path) => path,
             None => {
-                println!("WARNING: `CARGO_MANIFEST_DIR` env variable not set");
+                println!(
+                    "{}: `CARGO_MANIFEST_DIR` env variable not set",
+                    color::paint("WARNING", Color::Yellow, Stream::Stdout)
+                );
                 locate_cargo_manifest::locate_manifest()?
             }
         };
@@ -41,9 +62,49 @@ impl Builder {
         Ok(Builder {
             manifest_path,
             project_metadata: None,
+            metadata_options: Vec::new(),
+            config_overrides: Vec::new(),
+            debug_artifacts: false,
+            metadata_retries: 2,
+            deny_warnings: false,
         })
     }
 
+    /// Print the artifacts that cargo reports in its JSON output to stderr.
+    ///
+    /// This helps to diagnose why no or multiple executables were found.
+    pub fn set_debug_artifacts(&mut self, debug_artifacts: bool) {
+        self.debug_artifacts = debug_artifacts;
+    }
+
+    /// Sets the `--locked`/`--frozen`/`--offline` flags that are passed to `cargo metadata`.
+    ///
+    /// This keeps `cargo metadata` from updating the lockfile or accessing the network when
+    /// the kernel build is invoked with these flags.
+    pub fn set_metadata_options(&mut self, options: Vec<String>) {
+        self.metadata_options = options;
+    }
+
+    /// Sets the `--config` arguments of the kernel build (as `--config=VALUE`).
+    ///
+    /// These are also applied to the bootloader build so that config overrides affect the
+    /// whole build.
+    pub fn set_config_overrides(&mut self, config_overrides: Vec<String>) {
+        self.config_overrides = config_overrides;
+    }
+
+    /// Sets how often `cargo metadata` is retried after a transient (e.g. network) failure.
+    ///
+    /// Defaults to 2 retries.
+    pub fn set_metadata_retries(&mut self, retries: u32) {
+        self.metadata_retries = retries;
+    }
+
+    /// Fail the kernel and bootloader builds if the compiler emitted any warnings.
+    pub fn set_deny_warnings(&mut self, deny_warnings: bool) {
+        self.deny_warnings = deny_warnings;
+    }
+
     /// Returns the path to the Cargo.toml file of the project.
     pub fn manifest_path(&self) -> &Path {
         &self.manifest_path
@@ -52,7 +113,8 @@ impl Builder {
     /// Builds the kernel by executing `cargo build` with the given arguments.
     ///
     /// Returns a list of paths to all built executables. For crates with only a single binary,
-    /// the returned list contains only a single element.
+    /// the returned list contains only a single element. The configured `pre-build-command`
+    /// is run before the build.
     ///
     /// If the quiet argument is set to true, all output to stdout is suppressed.
     pub fn build_kernel(
@@ -61,25 +123,51 @@ impl Builder {
         config: &Config,
         quiet: bool,
     ) -> Result<Vec<PathBuf>, BuildKernelError> {
-        if !quiet {
-            println!("Building kernel");
+        if let Some(pre_build_command) = &config.pre_build_command {
+            let manifest_dir = match self.manifest_path.parent() {
+                Some(dir) if dir != Path::new("") => dir,
+                _ => Path::new("."),
+            };
+            run_pre_build_command(pre_build_command, manifest_dir, quiet)?;
         }
 
-        // try to build kernel
-        let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
-        let mut cmd = process::Command::new(&cargo);
-        cmd.args(&config.build_command);
-        cmd.args(args);
+        let kernel_build_command = kernel_build_command(config);
         if !quiet {
-            cmd.stdout(process::Stdio::inherit());
-            cmd.stderr(process::Stdio::inherit());
+            println!(
+                "Building kernel (using `cargo {}`)",
+                kernel_build_command.join(" ")
+            );
         }
-        let output = cmd.output().map_err(|err| BuildKernelError::Io {
-            message: "failed to execute kernel build",
-            error: err,
-        })?;
-        if !output.status.success() {
-            if config.build_command.starts_with(&["xbuild".into()]) {
+
+        // `RUSTC_BOOTSTRAP` allows `-Z` flags on stable toolchains
+        if kernel_uses_unstable_flags(args, &kernel_build_command)
+            && std::env::var_os("RUSTC_BOOTSTRAP").is_none()
+        {
+            match toolchain::rustc_release() {
+                Some(release) if !toolchain::is_nightly() => {
+                    return Err(BuildKernelError::NightlyRequired { release });
+                }
+                _ => {}
+            }
+        }
+        if kernel_uses_build_std(args, &kernel_build_command) && !toolchain::rust_src_installed() {
+            return Err(BuildKernelError::RustSrcNotInstalled);
+        }
+        let rustflags = kernel_rustflags(config)?;
+        let args = &resolve_target_args(args, config);
+
+        let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
+        let build_command = || {
+            let mut cmd = process::Command::new(&cargo);
+            cmd.args(&kernel_build_command);
+            cmd.args(args);
+            if let Some(rustflags) = &rustflags {
+                cmd.env("CARGO_ENCODED_RUSTFLAGS", rustflags);
+            }
+            cmd
+        };
+        let build_failed = |stderr: Vec<u8>| {
+            if kernel_build_command.starts_with(&["xbuild".into()]) {
                 // try executing `cargo xbuild --help` to check whether cargo-xbuild is installed
                 let mut help_command = process::Command::new("cargo");
                 help_command.arg("xbuild").arg("--help");
@@ -87,47 +175,80 @@ impl Builder {
                 help_command.stderr(process::Stdio::null());
                 if let Ok(help_exit_status) = help_command.status() {
                     if !help_exit_status.success() {
-                        return Err(BuildKernelError::XbuildNotFound);
+                        return BuildKernelError::XbuildNotFound;
                     }
                 }
             }
-            return Err(BuildKernelError::BuildFailed {
-                stderr: output.stderr,
-            });
-        }
+            BuildKernelError::BuildFailed { stderr }
+        };
 
-        // Retrieve binary paths
-        let mut cmd = process::Command::new(cargo);
-        cmd.args(&config.build_command);
-        cmd.args(args);
-        cmd.arg("--message-format").arg("json");
-        let output = cmd.output().map_err(|err| BuildKernelError::Io {
-            message: "failed to execute kernel build with json output",
-            error: err,
-        })?;
+        let output = if config.single_pass_build {
+            let mut cmd = build_command();
+            cmd.arg("--message-format").arg(json_message_format());
+            single_pass_build(cmd, quiet, false).map_err(|err| BuildKernelError::Io {
+                message: "failed to execute kernel build with json output",
+                error: err,
+            })?
+        } else {
+            // try to build kernel
+            let mut cmd = build_command();
+            if !quiet {
+                cmd.stdout(process::Stdio::inherit());
+                cmd.stderr(process::Stdio::inherit());
+            }
+            let output = cmd.output().map_err(|err| BuildKernelError::Io {
+                message: "failed to execute kernel build",
+                error: err,
+            })?;
+            if !output.status.success() {
+                return Err(build_failed(output.stderr));
+            }
+
+            // Retrieve binary paths
+            let mut cmd = build_command();
+            cmd.arg("--message-format").arg("json");
+            cmd.output().map_err(|err| BuildKernelError::Io {
+                message: "failed to execute kernel build with json output",
+                error: err,
+            })?
+        };
         if !output.status.success() {
-            return Err(BuildKernelError::BuildFailed {
-                stderr: output.stderr,
-            });
+            return Err(build_failed(output.stderr));
         }
         let mut executables = Vec::new();
+        let mut warnings = Vec::new();
         for line in String::from_utf8(output.stdout)
             .map_err(BuildKernelError::BuildJsonOutputInvalidUtf8)?
             .lines()
         {
             let mut artifact =
                 json::parse(line).map_err(BuildKernelError::BuildJsonOutputInvalidJson)?;
+            if self.debug_artifacts {
+                print_artifact("kernel", &artifact);
+            }
+            if self.deny_warnings {
+                warnings.extend(compiler_warning(&artifact));
+            }
             if let Some(executable) = artifact["executable"].take_string() {
                 executables.push(PathBuf::from(executable));
             }
         }
+        if !warnings.is_empty() {
+            return Err(BuildKernelError::WarningsDenied { warnings });
+        }
 
         Ok(executables)
     }
 
     /// Creates a bootimage by combining the given kernel binary with the bootloader.
     ///
-    /// Places the resulting bootable disk image at the given `output_bin_path`.
+    /// Places the resulting bootable disk image at the given `output_bin_path`. This uses the
+    /// default configuration, use [`Builder::create_bootimage_with_config`] for applying the
+    /// configuration of the kernel.
+    ///
+    /// With the default configuration, the bootloader is built with `-Zbuild-std` if the
+    /// active toolchain supports it (see `auto-build-std`) and the kernel executable must be
+    /// built for a bare-metal target that matches the bootloader.
     ///
     /// If the quiet argument is set to true, all output to stdout is suppressed.
     pub fn create_bootimage(
@@ -137,16 +258,668 @@ impl Builder {
         output_bin_path: &Path,
         quiet: bool,
     ) -> Result<(), CreateBootimageError> {
-        let bootloader_build_config = bootloader::BuildConfig::from_metadata(
-            self.project_metadata()?,
+        self.create_bootimage_with_config(
+            kernel_manifest_path,
+            bin_path,
+            output_bin_path,
+            &Config::default(),
+            quiet,
+        )
+    }
+
+    /// Creates a bootimage like [`create_bootimage`](Builder::create_bootimage), using the
+    /// given configuration.
+    pub fn create_bootimage_with_config(
+        &mut self,
+        kernel_manifest_path: &Path,
+        bin_path: &Path,
+        output_bin_path: &Path,
+        config: &Config,
+        quiet: bool,
+    ) -> Result<(), CreateBootimageError> {
+        self.create_bootimage_detailed(
             kernel_manifest_path,
             bin_path,
+            output_bin_path,
+            config,
+            quiet,
         )?;
+        Ok(())
+    }
 
-        // build bootloader
-        if !quiet {
-            println!("Building bootloader");
+    /// Creates a bootimage like [`Builder::create_bootimage_with_config`], but returns
+    /// information about the created image.
+    ///
+    /// The returned bootloader ELF path can be used e.g. for loading the bootloader symbols
+    /// in GDB.
+    pub fn create_bootimage_detailed(
+        &mut self,
+        kernel_manifest_path: &Path,
+        bin_path: &Path,
+        output_bin_path: &Path,
+        config: &Config,
+        quiet: bool,
+    ) -> Result<BootImage, CreateBootimageError> {
+        // the bootloader embeds a stripped copy of the kernel if the symbols are split off
+        let (kernel_bin_path, symbol_path, stripped_kernel_path) = if config.strip_symbols {
+            let symbol_path = output_bin_path.with_extension("sym");
+            let stripped_path = output_bin_path.with_extension("stripped.elf");
+            disk_image::split_debug_symbols(bin_path, &symbol_path, &stripped_path)?;
+            if !quiet {
+                println!(
+                    "Created symbol file at `{}` and stripped kernel at `{}`",
+                    symbol_path.display(),
+                    stripped_path.display()
+                );
+            }
+            (
+                stripped_path.clone(),
+                Some(symbol_path),
+                Some(stripped_path),
+            )
+        } else {
+            (bin_path.to_owned(), None, None)
+        };
+
+        let mut bootloader_build_config = bootloader::BuildConfig::from_metadata(
+            self.project_metadata()?,
+            kernel_manifest_path,
+            &kernel_bin_path,
+            config,
+        )?;
+        bootloader_build_config.set_config_overrides(self.config_overrides.clone());
+        check_kernel_target(bin_path, bootloader_build_config.target_arch().as_deref())?;
+
+        let cache_path = if config.cache {
+            let path =
+                bootloader_build_config
+                    .cache_path()
+                    .map_err(|err| CreateBootimageError::Io {
+                        message: "failed to compute bootloader cache key",
+                        error: err,
+                    })?;
+            Some(path)
+        } else {
+            None
+        };
+        let bootloader_elf_path = match cache_path {
+            Some(cache_path) if cache_path.exists() => {
+                if !quiet {
+                    println!("Using cached bootloader `{}`", cache_path.display());
+                }
+                cache_path
+            }
+            cache_path => {
+                let bootloader_elf_path = build_bootloader(
+                    &bootloader_build_config,
+                    quiet,
+                    self.debug_artifacts,
+                    self.deny_warnings,
+                    config.single_pass_build,
+                )?;
+                if let Some(cache_path) = cache_path {
+                    store_in_cache(&bootloader_elf_path, &cache_path)?;
+                }
+                bootloader_elf_path
+            }
+        };
+
+        let format = if config.legacy_image {
+            disk_image::create_legacy_disk_image(
+                &bootloader_elf_path,
+                &kernel_bin_path,
+                output_bin_path,
+            )?;
+            ImageFormat::Legacy
+        } else {
+            let arch = config
+                .arch
+                .or_else(|| {
+                    bootloader_build_config
+                        .target_arch()
+                        .as_deref()
+                        .and_then(Arch::from_target_triple)
+                })
+                .unwrap_or(Arch::X86_64);
+            disk_image::create_disk_image(
+                &bootloader_elf_path,
+                output_bin_path,
+                arch,
+                config.image_start_offset,
+                config.check_boot_signature && arch == Arch::X86_64,
+                quiet,
+            )?;
+            ImageFormat::Raw
+        };
+
+        if let Some(post_image_command) = &config.post_image_command {
+            run_post_image_command(post_image_command, output_bin_path, quiet)?;
+        }
+
+        let mut mbr_image_path = None;
+        if config.partition_table == PartitionTable::Mbr {
+            let path = output_bin_path.with_extension("mbr.img");
+            disk_image::create_mbr_image(
+                output_bin_path,
+                &path,
+                config.partition_start_lba,
+                quiet,
+            )?;
+            if !quiet {
+                println!("Created MBR image at `{}`", path.display());
+            }
+            mbr_image_path = Some(path);
+        }
+
+        let converted_image_path = match config.image_type {
+            ImageType::Raw => None,
+            image_type => {
+                let path = output_bin_path.with_extension(image_type.name());
+                disk_image::convert_image(output_bin_path, &path, image_type.qemu_img_format())?;
+                if !quiet {
+                    println!(
+                        "Converted disk image to {} at `{}`",
+                        image_type.name(),
+                        path.display()
+                    );
+                }
+                Some(path)
+            }
+        };
+
+        let mut additional_image_paths = Vec::new();
+        for image in &config.additional_images {
+            let file_stem = output_bin_path.file_stem().unwrap_or_default();
+            let mut file_name = file_stem.to_owned();
+            file_name.push(&image.suffix);
+            let image_path = output_bin_path.with_file_name(file_name);
+            disk_image::create_additional_image(
+                output_bin_path,
+                &image_path,
+                image.min_size,
+                image.block_size,
+                quiet,
+            )?;
+            if !quiet {
+                println!("Created additional image at `{}`", image_path.display());
+            }
+            additional_image_paths.push(image_path);
+        }
+
+        Ok(BootImage {
+            image_path: output_bin_path.to_owned(),
+            bootloader_elf_path,
+            format,
+            symbol_path,
+            stripped_kernel_path,
+            mbr_image_path,
+            converted_image_path,
+            additional_image_paths,
+        })
+    }
+
+    /// Returns the cargo metadata package that contains the given binary.
+    ///
+    /// Both `bin` and `example` targets are considered. If `packages` is not empty (e.g.
+    /// because of `--package` arguments), only the given packages are searched, which
+    /// selects the right package if multiple workspace members have a binary of that name.
+    /// Package specs of the form `name@version` are matched by their name.
+    pub fn kernel_package_for_bin(
+        &mut self,
+        kernel_bin_name: &str,
+        packages: &[String],
+    ) -> Result<Option<&cargo_metadata::Package>, cargo_metadata::Error> {
+        let selected = |package: &cargo_metadata::Package| {
+            packages.is_empty()
+                || packages
+                    .iter()
+                    .any(|spec| spec.split('@').next() == Some(package.name.as_str()))
+        };
+        Ok(self.project_metadata()?.packages.iter().find(|p| {
+            selected(p)
+                && p.targets.iter().any(|t| {
+                    t.name == kernel_bin_name && t.kind.iter().any(|k| k == "bin" || k == "example")
+                })
+        }))
+    }
+
+    /// Returns the paths of the executables that `build_kernel` would build with the given
+    /// arguments and cargo profile, without building anything.
+    ///
+    /// The paths are predicted from the `cargo metadata` output and the `--target`,
+    /// `--package`, `--bin(s)`, and `--example(s)` arguments. If no `--target` is given, the
+    /// `CARGO_BUILD_TARGET` environment variable and the `build.target` key of the cargo
+    /// configuration are used.
+    pub fn predict_executables(
+        &mut self,
+        args: &[String],
+        profile: &str,
+    ) -> Result<Vec<PathBuf>, cargo_metadata::Error> {
+        let packages: Vec<_> = arg_values(args, "--package")
+            .into_iter()
+            .chain(arg_values(args, "-p"))
+            .collect();
+        let bins = arg_values(args, "--bin");
+        let examples = arg_values(args, "--example");
+        let has_flag = |flag: &str| {
+            args.iter()
+                .take_while(|arg| *arg != "--")
+                .any(|arg| arg == flag)
+        };
+        let all_bins = has_flag("--bins");
+        let all_examples = has_flag("--examples");
+        let default_bins = bins.is_empty() && examples.is_empty() && !all_examples;
+        let target = arg_values(args, "--target")
+            .last()
+            .map(|target| target.to_string())
+            .or_else(|| std::env::var("CARGO_BUILD_TARGET").ok())
+            .or_else(|| {
+                cargo_config_value("build", "target")?
+                    .as_str()
+                    .map(String::from)
+            });
+        let manifest_path = self
+            .manifest_path
+            .canonicalize()
+            .unwrap_or_else(|_| self.manifest_path.clone());
+
+        let metadata = self.project_metadata()?;
+        let mut out_dir = metadata.target_directory.clone();
+        if let Some(target) = target {
+            let target = Path::new(&target);
+            // custom targets are placed in a directory named after the JSON file
+            match target.extension() {
+                Some(ext) if ext == "json" => out_dir.extend(target.file_stem()),
+                _ => out_dir.push(target),
+            }
+        }
+        out_dir.push(match profile {
+            "dev" | "test" => "debug",
+            "bench" => "release",
+            other => other,
+        });
+
+        let members: Vec<_> = metadata
+            .packages
+            .iter()
+            .filter(|package| metadata.workspace_members.contains(&package.id))
+            .collect();
+        let is_root = |package: &cargo_metadata::Package| package.manifest_path == manifest_path;
+        let selected = members.iter().filter(|package| {
+            if !packages.is_empty() {
+                packages.contains(&package.name.as_str())
+            } else {
+                // virtual workspaces build all members
+                is_root(package) || !members.iter().any(|package| is_root(package))
+            }
+        });
+
+        let mut executables = Vec::new();
+        for package in selected {
+            for target in &package.targets {
+                let name = target.name.as_str();
+                if target.kind.iter().any(|kind| kind == "bin")
+                    && (default_bins || all_bins || bins.contains(&name))
+                {
+                    executables.push(out_dir.join(name));
+                }
+                if target.kind.iter().any(|kind| kind == "example")
+                    && (all_examples || examples.contains(&name))
+                {
+                    executables.push(out_dir.join("examples").join(name));
+                }
+            }
+        }
+        Ok(executables)
+    }
+
+    fn project_metadata(&mut self) -> Result<&Metadata, cargo_metadata::Error> {
+        if let Some(ref metadata) = self.project_metadata {
+            return Ok(metadata);
         }
+        let mut command = self.metadata_command();
+        let mut retries = self.metadata_retries;
+        let metadata = loop {
+            match command.exec() {
+                Ok(metadata) => break metadata,
+                Err(err) if retries > 0 && is_transient_metadata_error(&err) => {
+                    retries -= 1;
+                    eprintln!(
+                        "{}: `cargo metadata` failed with a network error, retrying in {}s",
+                        color::paint("WARNING", Color::Yellow, Stream::Stderr),
+                        METADATA_RETRY_BACKOFF.as_secs()
+                    );
+                    thread::sleep(METADATA_RETRY_BACKOFF);
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        Ok(self.project_metadata.get_or_insert(metadata))
+    }
+
+    fn metadata_command(&self) -> cargo_metadata::MetadataCommand {
+        let mut command = cargo_metadata::MetadataCommand::new();
+        command
+            .manifest_path(&self.manifest_path)
+            .other_options(self.metadata_options.clone());
+        command
+    }
+}
+
+/// Checks that the given kernel executable was built for a bare-metal target that matches the
+/// architecture of the bootloader.
+///
+/// The machine check is skipped if the bootloader architecture is unknown.
+fn check_kernel_target(
+    kernel_bin_path: &Path,
+    bootloader_arch: Option<&str>,
+) -> Result<(), CreateBootimageError> {
+    let kernel = fs::read(kernel_bin_path).map_err(|err| CreateBootimageError::Io {
+        message: "failed to read kernel executable",
+        error: err,
+    })?;
+    match kernel_target_mismatch(&kernel, bootloader_arch) {
+        Some(reason) => Err(CreateBootimageError::WrongKernelTarget {
+            path: kernel_bin_path.to_owned(),
+            reason,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Returns why the given kernel executable doesn't match the bootloader architecture, if it
+/// doesn't.
+fn kernel_target_mismatch(kernel: &[u8], bootloader_arch: Option<&str>) -> Option<String> {
+    let elf = match elf::ElfFile::new(kernel) {
+        Ok(elf) => elf,
+        Err(err) => return Some(err.into()),
+    };
+
+    let expected_machine = match bootloader_arch {
+        Some("x86_64") => Some(62),
+        Some("aarch64") => Some(183),
+        Some("riscv64") => Some(243),
+        _ => None,
+    };
+    let machine = match elf.machine() {
+        Ok(machine) => machine,
+        Err(err) => return Some(err.into()),
+    };
+    if let (Some(expected), Some(arch)) = (expected_machine, bootloader_arch) {
+        if machine != expected {
+            return Some(format!(
+                "its ELF machine type is {}, but the bootloader expects a kernel for {}",
+                machine, arch
+            ));
+        }
+    }
+    match elf.has_interpreter() {
+        Ok(false) => None,
+        Ok(true) => Some(
+            "it requests a program interpreter, so it was built for a hosted target (e.g. Linux)"
+                .into(),
+        ),
+        Err(err) => Some(err.into()),
+    }
+}
+
+/// Returns whether the given `cargo metadata` error is caused by a network or registry
+/// failure that might not occur again on a retry.
+///
+/// Other errors (e.g. an invalid manifest) are permanent and should not be retried.
+fn is_transient_metadata_error(err: &cargo_metadata::Error) -> bool {
+    const TRANSIENT_MESSAGES: &[&str] = &[
+        "failed to download",
+        "failed to fetch",
+        "failed to update registry",
+        "failed to query replaced source registry",
+        "spurious network error",
+        "network failure",
+        "Couldn't resolve host",
+        "Connection reset",
+        "timed out",
+    ];
+    match err {
+        cargo_metadata::Error::CargoMetadata { stderr } => TRANSIENT_MESSAGES
+            .iter()
+            .any(|message| stderr.contains(message)),
+        _ => false,
+    }
+}
+
+/// Information about a disk image created by [`Builder::create_bootimage_detailed`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct BootImage {
+    /// The path to the created disk image
+    pub image_path: PathBuf,
+    /// The path to the bootloader ELF executable that the disk image was created from
+    pub bootloader_elf_path: PathBuf,
+    /// The format of the created disk image
+    pub format: ImageFormat,
+    /// The path to the separate kernel symbol file if `strip-symbols` is enabled
+    pub symbol_path: Option<PathBuf>,
+    /// The path to the stripped kernel executable if `strip-symbols` is enabled
+    pub stripped_kernel_path: Option<PathBuf>,
+    /// The path to the MBR image if `partition-table = "mbr"` is set
+    pub mbr_image_path: Option<PathBuf>,
+    /// The path to the image converted through `qemu-img` if `image-type` is not `raw`
+    pub converted_image_path: Option<PathBuf>,
+    /// The paths to the images created for `additional-images`
+    pub additional_image_paths: Vec<PathBuf>,
+}
+
+/// The format of a created disk image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// The bootloader executable converted to a raw binary (the default)
+    Raw,
+    /// The format for old bootloader versions (see the `legacy-image` config key)
+    Legacy,
+}
+
+/// Returns the cargo subcommand and flags that are used for building the kernel.
+///
+/// Like for the bootloader, a `cargo xbuild` build command is replaced with
+/// `cargo build -Zbuild-std` if the active toolchain supports it, unless this is disabled
+/// through the `auto-build-std` config key.
+fn kernel_build_command(config: &Config) -> Vec<String> {
+    match config.build_command.split_first() {
+        Some((subcommand, rest))
+            if subcommand == "xbuild"
+                && config.auto_build_std
+                && toolchain::supports_build_std() =>
+        {
+            let mut command = vec![
+                "build".to_owned(),
+                format!("-Zbuild-std={}", toolchain::BUILD_STD_CRATES),
+                format!("-Zbuild-std-features={}", toolchain::BUILD_STD_FEATURES),
+            ];
+            command.extend_from_slice(rest);
+            command
+        }
+        _ => config.build_command.clone(),
+    }
+}
+
+/// Returns whether the kernel build uses unstable `-Z` flags, which require nightly.
+///
+/// Besides `-Z` arguments, this includes a `-Zbuild-std` that is set through the `unstable`
+/// table of the cargo configuration.
+fn kernel_uses_unstable_flags(args: &[String], build_command: &[String]) -> bool {
+    let in_args = build_command
+        .iter()
+        .chain(args)
+        .take_while(|arg| *arg != "--")
+        .any(|arg| arg.starts_with("-Z"));
+    in_args || kernel_uses_build_std(args, build_command)
+}
+
+/// Returns whether the kernel build uses `-Zbuild-std`.
+///
+/// Checks the build arguments, the `build-command`, and the `unstable.build-std` key in the
+/// cargo configuration files of the current directory and its parents.
+fn kernel_uses_build_std(args: &[String], build_command: &[String]) -> bool {
+    let is_build_std =
+        |flag: &str| flag.starts_with("build-std") && !flag.starts_with("build-std-features");
+    let args: Vec<_> = build_command
+        .iter()
+        .chain(args)
+        .take_while(|arg| *arg != "--")
+        .collect();
+    let in_args = args
+        .iter()
+        .enumerate()
+        .any(|(index, arg)| match arg.strip_prefix("-Z") {
+            Some("") => args.get(index + 1).is_some_and(|flag| is_build_std(flag)),
+            Some(flag) => is_build_std(flag),
+            None => false,
+        });
+    if in_args {
+        return true;
+    }
+    cargo_config_value("unstable", "build-std").is_some()
+}
+
+/// Returns the value of the given key in the cargo configuration files of the current
+/// directory and its parents.
+///
+/// Files closer to the current directory take precedence.
+fn cargo_config_value(table: &str, key: &str) -> Option<toml::Value> {
+    let current_dir = std::env::current_dir().ok()?;
+    current_dir.ancestors().find_map(|dir| {
+        ["config.toml", "config"].iter().find_map(|name| {
+            fs::read_to_string(dir.join(".cargo").join(name))
+                .ok()
+                .and_then(|content| content.parse::<toml::Value>().ok())
+                .and_then(|value| value.get(table)?.get(key).cloned())
+        })
+    })
+}
+
+/// Returns the values of the given argument in both the `NAME VALUE` and `NAME=VALUE` forms.
+///
+/// Arguments after a `--` are ignored.
+fn arg_values<'a>(args: &'a [String], name: &str) -> Vec<&'a str> {
+    let mut values = Vec::new();
+    let mut iter = args.iter().take_while(|arg| *arg != "--");
+    while let Some(arg) = iter.next() {
+        if arg == name {
+            values.extend(iter.next().map(String::as_str));
+        } else if let Some(value) = arg
+            .strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            values.push(value);
+        }
+    }
+    values
+}
+
+/// Resolves bare `--target` names to JSON files in the configured `target-json-dir`.
+///
+/// Targets that end in `.json`, are built into `rustc`, or have no corresponding JSON file
+/// in the directory are passed through unchanged.
+fn resolve_target_args(args: &[String], config: &Config) -> Vec<String> {
+    let target_json_dir = match &config.target_json_dir {
+        Some(dir) => dir,
+        None => return args.to_vec(),
+    };
+    let resolve = |target: &str| -> Option<String> {
+        if target.ends_with(".json") || toolchain::is_builtin_target(target) {
+            return None;
+        }
+        let path = target_json_dir.join(format!("{}.json", target));
+        if path.is_file() {
+            Some(path.display().to_string())
+        } else {
+            None
+        }
+    };
+
+    let mut resolved = Vec::with_capacity(args.len());
+    let mut iter = args.iter().take_while(|arg| *arg != "--");
+    while let Some(arg) = iter.next() {
+        if arg == "--target" {
+            resolved.push(arg.clone());
+            if let Some(target) = iter.next() {
+                resolved.push(resolve(target).unwrap_or_else(|| target.clone()));
+            }
+        } else if let Some(target) = arg.strip_prefix("--target=") {
+            match resolve(target) {
+                Some(path) => resolved.push(format!("--target={}", path)),
+                None => resolved.push(arg.clone()),
+            }
+        } else {
+            resolved.push(arg.clone());
+        }
+    }
+    resolved.extend(args[resolved.len()..].iter().cloned());
+    resolved
+}
+
+/// Returns the encoded rustflags for the kernel build if the config requires additional flags.
+///
+/// The additional flags are appended to the flags from the `CARGO_ENCODED_RUSTFLAGS` or
+/// `RUSTFLAGS` environment variables. The encoded form (separated by `0x1f`) allows paths
+/// with spaces.
+fn kernel_rustflags(config: &Config) -> Result<Option<String>, BuildKernelError> {
+    let mut extra_flags = Vec::new();
+    if let Some(linker_script) = &config.kernel_linker_script {
+        if !linker_script.is_file() {
+            return Err(BuildKernelError::LinkerScriptNotFound {
+                path: linker_script.clone(),
+            });
+        }
+        extra_flags.push(format!("-Clink-arg=-T{}", linker_script.display()));
+    }
+    if !config.kernel_target_features.is_empty() {
+        extra_flags.push(format!(
+            "-Ctarget-feature={}",
+            config.kernel_target_features.join(",")
+        ));
+    }
+    if extra_flags.is_empty() {
+        return Ok(None);
+    }
+
+    let mut flags: Vec<String> = match std::env::var("CARGO_ENCODED_RUSTFLAGS") {
+        Ok(encoded) if !encoded.is_empty() => encoded.split('\x1f').map(String::from).collect(),
+        _ => std::env::var("RUSTFLAGS")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(String::from)
+            .collect(),
+    };
+    flags.extend(extra_flags);
+    Ok(Some(flags.join("\x1f")))
+}
+
+/// Builds the bootloader and returns the path to the resulting ELF executable.
+fn build_bootloader(
+    bootloader_build_config: &bootloader::BuildConfig,
+    quiet: bool,
+    debug_artifacts: bool,
+    deny_warnings: bool,
+    single_pass: bool,
+) -> Result<PathBuf, CreateBootimageError> {
+    if !quiet {
+        println!(
+            "Building bootloader (using `{}`)",
+            bootloader_build_config.build_mechanism()
+        );
+    }
+    if bootloader_build_config.uses_build_std() && !toolchain::rust_src_installed() {
+        return Err(CreateBootimageError::RustSrcNotInstalled);
+    }
+    let output = if single_pass {
+        let mut cmd = bootloader_build_config.build_command();
+        cmd.arg("--message-format").arg(json_message_format());
+        single_pass_build(cmd, quiet, true).map_err(|err| CreateBootimageError::Io {
+            message: "failed to execute bootloader build command with json output",
+            error: err,
+        })?
+    } else {
         let mut cmd = bootloader_build_config.build_command();
         if !quiet {
             cmd.stdout(process::Stdio::inherit());
@@ -161,66 +934,399 @@ impl Builder {
                 stderr: output.stderr,
             });
         }
+        // The `--quiet` flag only suppresses output to stdout, so we still want to surface
+        // warnings (e.g. deprecations) that the bootloader build printed to stderr. In the
+        // non-quiet case, stderr is already inherited.
+        if quiet {
+            if let Some(warnings) = warning_output(&output.stderr) {
+                eprint!("{}", warnings);
+            }
+        }
 
         // Retrieve binary path
         let mut cmd = bootloader_build_config.build_command();
         cmd.arg("--message-format").arg("json");
-        let output = cmd.output().map_err(|err| CreateBootimageError::Io {
+        cmd.output().map_err(|err| CreateBootimageError::Io {
             message: "failed to execute bootloader build command with json output",
             error: err,
-        })?;
-        if !output.status.success() {
-            return Err(CreateBootimageError::BootloaderBuildFailed {
-                stderr: output.stderr,
-            });
+        })?
+    };
+    if !output.status.success() {
+        return Err(CreateBootimageError::BootloaderBuildFailed {
+            stderr: output.stderr,
+        });
+    }
+    let mut bootloader_elf_path = None;
+    let mut warnings = Vec::new();
+    for line in String::from_utf8(output.stdout)
+        .map_err(CreateBootimageError::BuildJsonOutputInvalidUtf8)?
+        .lines()
+    {
+        let mut artifact =
+            json::parse(line).map_err(CreateBootimageError::BuildJsonOutputInvalidJson)?;
+        if debug_artifacts {
+            print_artifact("bootloader", &artifact);
         }
-        let mut bootloader_elf_path = None;
-        for line in String::from_utf8(output.stdout)
-            .map_err(CreateBootimageError::BuildJsonOutputInvalidUtf8)?
-            .lines()
-        {
-            let mut artifact =
-                json::parse(line).map_err(CreateBootimageError::BuildJsonOutputInvalidJson)?;
-            if let Some(executable) = artifact["executable"].take_string() {
-                if bootloader_elf_path
-                    .replace(PathBuf::from(executable))
-                    .is_some()
-                {
-                    return Err(BootloaderError::BootloaderInvalid(
-                        "bootloader has multiple executables".into(),
-                    )
-                    .into());
+        if deny_warnings {
+            warnings.extend(compiler_warning(&artifact));
+        }
+        if let Some(executable) = artifact["executable"].take_string() {
+            if bootloader_elf_path
+                .replace(PathBuf::from(executable))
+                .is_some()
+            {
+                return Err(BootloaderError::BootloaderInvalid(
+                    "bootloader has multiple executables".into(),
+                )
+                .into());
+            }
+        }
+    }
+    if !warnings.is_empty() {
+        return Err(CreateBootimageError::BootloaderWarningsDenied { warnings });
+    }
+    let bootloader_elf_path = bootloader_elf_path
+        .ok_or_else(|| BootloaderError::BootloaderInvalid("bootloader has no executable".into()))?;
+    Ok(bootloader_elf_path)
+}
+
+/// Returns the rendered diagnostic if the given cargo JSON message is a compiler warning.
+fn compiler_warning(message: &json::JsonValue) -> Option<String> {
+    if message["reason"] != "compiler-message" || message["message"]["level"] != "warning" {
+        return None;
+    }
+    let diagnostic = &message["message"];
+    diagnostic["rendered"]
+        .as_str()
+        .or_else(|| diagnostic["message"].as_str())
+        .map(String::from)
+}
+
+/// Prints the name, kinds, and executable of the given cargo JSON artifact to stderr.
+fn print_artifact(build: &str, artifact: &json::JsonValue) {
+    if artifact["reason"] != "compiler-artifact" {
+        return;
+    }
+    let kinds: Vec<_> = artifact["target"]["kind"]
+        .members()
+        .filter_map(|kind| kind.as_str())
+        .collect();
+    eprintln!(
+        "[{} artifact] name: {}, kind: [{}], executable: {}",
+        build,
+        artifact["target"]["name"],
+        kinds.join(", "),
+        artifact["executable"].as_str().unwrap_or("none"),
+    );
+}
+
+/// Runs the configured `pre-build-command` in the given directory.
+fn run_pre_build_command(
+    pre_build_command: &[String],
+    manifest_dir: &Path,
+    quiet: bool,
+) -> Result<(), BuildKernelError> {
+    let (program, args) = pre_build_command
+        .split_first()
+        .ok_or(BuildKernelError::PreBuildCommandEmpty)?;
+    if !quiet {
+        println!(
+            "Running pre-build command: `{}`",
+            pre_build_command.join(" ")
+        );
+    }
+    let mut cmd = process::Command::new(program);
+    cmd.args(args);
+    cmd.current_dir(manifest_dir);
+    if !quiet {
+        cmd.stdout(process::Stdio::inherit());
+        cmd.stderr(process::Stdio::inherit());
+    }
+    let output = cmd.output().map_err(|err| BuildKernelError::Io {
+        message: "failed to execute pre-build command",
+        error: err,
+    })?;
+    if !output.status.success() {
+        return Err(BuildKernelError::PreBuildCommandFailed {
+            command: pre_build_command.join(" "),
+            stderr: output.stderr,
+        });
+    }
+    Ok(())
+}
+
+/// Runs the configured `post-image-command` on the given disk image.
+fn run_post_image_command(
+    post_image_command: &[String],
+    image_path: &Path,
+    quiet: bool,
+) -> Result<(), CreateBootimageError> {
+    let command: Vec<_> = post_image_command
+        .iter()
+        .map(|arg| arg.replace("{bootimage}", &image_path.display().to_string()))
+        .collect();
+    let (program, args) = command
+        .split_first()
+        .ok_or(CreateBootimageError::PostImageCommandEmpty)?;
+    if !quiet {
+        println!("Running post-image command: `{}`", command.join(" "));
+    }
+    let mut cmd = process::Command::new(program);
+    cmd.args(args);
+    if !quiet {
+        cmd.stdout(process::Stdio::inherit());
+        cmd.stderr(process::Stdio::inherit());
+    }
+    let output = cmd.output().map_err(|err| CreateBootimageError::Io {
+        message: "failed to execute post-image command",
+        error: err,
+    })?;
+    if !output.status.success() {
+        return Err(CreateBootimageError::PostImageCommandFailed {
+            command: command.join(" "),
+            stderr: output.stderr,
+        });
+    }
+    Ok(())
+}
+
+/// Extracts the warning diagnostics from the given cargo stderr output.
+fn warning_output(stderr: &[u8]) -> Option<String> {
+    let stderr = String::from_utf8_lossy(stderr);
+    let mut warnings = String::new();
+    let mut in_warning = false;
+    for line in stderr.lines() {
+        if line.starts_with("warning") {
+            in_warning = true;
+        }
+        if in_warning {
+            warnings.push_str(line);
+            warnings.push('\n');
+            if line.trim().is_empty() {
+                in_warning = false;
+            }
+        }
+    }
+    if warnings.is_empty() {
+        None
+    } else {
+        Some(warnings)
+    }
+}
+
+/// Returns the `--message-format` value for single-pass builds.
+///
+/// The rendered diagnostics contain ANSI color codes if colored output to stderr is enabled.
+fn json_message_format() -> &'static str {
+    if color::enabled(Stream::Stderr) {
+        "json-diagnostic-rendered-ansi"
+    } else {
+        "json"
+    }
+}
+
+/// Runs the given cargo build command, which must use a JSON `--message-format`, in a single
+/// pass.
+///
+/// The rendered compiler diagnostics of `compiler-message` entries are printed to stderr while
+/// the build runs. If `quiet` is set, they are only added to the returned stderr instead, with
+/// the exception of warnings if `forward_warnings` is set. The cargo stderr (e.g. the progress
+/// output) is inherited unless `quiet` is set. The returned stdout contains all JSON lines.
+fn single_pass_build(
+    mut cmd: process::Command,
+    quiet: bool,
+    forward_warnings: bool,
+) -> io::Result<process::Output> {
+    cmd.stdout(process::Stdio::piped());
+    if quiet {
+        cmd.stderr(process::Stdio::piped());
+    } else {
+        cmd.stderr(process::Stdio::inherit());
+    }
+    let mut child = cmd.spawn()?;
+
+    // read stderr concurrently to prevent a deadlock when the pipe buffer is full
+    let stderr_reader = child.stderr.take().map(|mut stderr| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            stderr.read_to_end(&mut buf).map(|_| buf)
+        })
+    });
+
+    let mut stdout = Vec::new();
+    let mut diagnostics = String::new();
+    let reader = io::BufReader::new(child.stdout.take().expect("stdout is piped"));
+    for line in reader.lines() {
+        let line = line?;
+        if let Ok(message) = json::parse(&line) {
+            if message["reason"] == "compiler-message" {
+                if let Some(rendered) = message["message"]["rendered"].as_str() {
+                    let is_warning = message["message"]["level"] == "warning";
+                    if !quiet || (forward_warnings && is_warning) {
+                        eprint!("{}", rendered);
+                    } else {
+                        diagnostics.push_str(rendered);
+                    }
                 }
             }
         }
-        let bootloader_elf_path = bootloader_elf_path.ok_or_else(|| {
-            BootloaderError::BootloaderInvalid("bootloader has no executable".into())
+        stdout.extend_from_slice(line.as_bytes());
+        stdout.push(b'\n');
+    }
+
+    let status = child.wait()?;
+    let mut stderr = diagnostics.into_bytes();
+    if let Some(stderr_reader) = stderr_reader {
+        let cargo_stderr = stderr_reader
+            .join()
+            .map_err(|_| io::Error::other("failed to read stderr of build command"))??;
+        stderr.extend_from_slice(&cargo_stderr);
+    }
+    Ok(process::Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+/// Copies the given bootloader executable to the given cache path.
+fn store_in_cache(
+    bootloader_elf_path: &Path,
+    cache_path: &Path,
+) -> Result<(), CreateBootimageError> {
+    if let Some(cache_dir) = cache_path.parent() {
+        fs::create_dir_all(cache_dir).map_err(|err| CreateBootimageError::Io {
+            message: "failed to create bootloader cache directory",
+            error: err,
         })?;
+    }
+    fs::copy(bootloader_elf_path, cache_path).map_err(|err| CreateBootimageError::Io {
+        message: "failed to copy bootloader to cache",
+        error: err,
+    })?;
+    Ok(())
+}
 
-        disk_image::create_disk_image(&bootloader_elf_path, output_bin_path)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(())
+    #[test]
+    fn metadata_options_reach_metadata_command() {
+        let mut builder = Builder::new(Some(PathBuf::from("Cargo.toml"))).unwrap();
+        builder.set_metadata_options(vec!["--locked".into()]);
+        let command = format!("{:?}", builder.metadata_command());
+        assert!(command.contains("\"--locked\""), "{}", command);
     }
 
-    /// Returns the cargo metadata package that contains the given binary.
-    pub fn kernel_package_for_bin(
-        &mut self,
-        kernel_bin_name: &str,
-    ) -> Result<Option<&cargo_metadata::Package>, cargo_metadata::Error> {
-        Ok(self.project_metadata()?.packages.iter().find(|p| {
-            p.targets
-                .iter()
-                .any(|t| t.name == kernel_bin_name && t.kind.iter().any(|k| k == "bin"))
-        }))
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&arg| arg.to_owned()).collect()
     }
 
-    fn project_metadata(&mut self) -> Result<&Metadata, cargo_metadata::Error> {
-        if let Some(ref metadata) = self.project_metadata {
-            return Ok(metadata);
+    #[test]
+    fn arg_value_forms() {
+        let args = args(&[
+            "--bin", "a", "--bin=b", "--bins", "--binary", "c", "--", "--bin", "d",
+        ]);
+        assert_eq!(arg_values(&args, "--bin"), ["a", "b"]);
+        assert_eq!(arg_values(&args, "--binary"), ["c"]);
+        assert_eq!(arg_values(&args[..1], "--bin"), Vec::<&str>::new());
+    }
+
+    /// Creates a workspace with a `kernel` package (bins `kernel` and `other`, example
+    /// `demo`) and a `member` package (bin `member`).
+    fn create_workspace(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("bootimage-{}-{}", name, process::id()));
+        let files = [
+            (
+                "Cargo.toml",
+                "[package]\nname = \"kernel\"\nversion = \"0.1.0\"\n\n\
+                 [workspace]\nmembers = [\"member\"]\n",
+            ),
+            ("src/main.rs", ""),
+            ("src/bin/other.rs", ""),
+            ("examples/demo.rs", ""),
+            (
+                "member/Cargo.toml",
+                "[package]\nname = \"member\"\nversion = \"0.1.0\"\n",
+            ),
+            ("member/src/main.rs", ""),
+        ];
+        for (path, content) in &files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
         }
-        let metadata = cargo_metadata::MetadataCommand::new()
-            .manifest_path(&self.manifest_path)
-            .exec()?;
-        Ok(self.project_metadata.get_or_insert(metadata))
+        root
+    }
+
+    #[test]
+    fn predicted_executables() {
+        let root = create_workspace("predict");
+        let mut builder = Builder::new(Some(root.join("Cargo.toml"))).unwrap();
+        builder.set_metadata_options(vec!["--offline".into()]);
+        let out_dir = builder
+            .project_metadata()
+            .unwrap()
+            .target_directory
+            .join("x86_64-os")
+            .join("debug");
+        let mut predict = |extra_args: &[&str]| {
+            let mut cargo_args = args(&["--target", "x86_64-os.json"]);
+            cargo_args.extend(args(extra_args));
+            let mut executables: Vec<_> = builder
+                .predict_executables(&cargo_args, "dev")
+                .unwrap()
+                .into_iter()
+                .map(|path| path.strip_prefix(&out_dir).unwrap().to_owned())
+                .collect();
+            executables.sort();
+            executables
+        };
+        let paths = |paths: &[&str]| -> Vec<PathBuf> { paths.iter().map(PathBuf::from).collect() };
+
+        assert_eq!(predict(&[]), paths(&["kernel", "other"]));
+        assert_eq!(predict(&["--bin", "other"]), paths(&["other"]));
+        assert_eq!(predict(&["--bins"]), paths(&["kernel", "other"]));
+        assert_eq!(predict(&["--package", "member"]), paths(&["member"]));
+        assert_eq!(
+            predict(&["-p", "kernel", "--package=member", "--bins"]),
+            paths(&["kernel", "member", "other"])
+        );
+        assert_eq!(predict(&["--example", "demo"]), paths(&["examples/demo"]));
+        assert_eq!(
+            predict(&["--example=demo", "--bin=kernel"]),
+            paths(&["examples/demo", "kernel"])
+        );
+        assert_eq!(
+            predict(&["--bins", "--examples"]),
+            paths(&["examples/demo", "kernel", "other"])
+        );
+        assert_eq!(
+            predict(&["--package", "member", "--example", "demo"]),
+            Vec::<PathBuf>::new()
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn kernel_machine_mismatch() {
+        let mut kernel = vec![0; 64];
+        kernel[0..4].copy_from_slice(b"\x7fELF");
+        kernel[4] = 2;
+        kernel[5] = 1;
+        kernel[0x12..0x14].copy_from_slice(&183u16.to_le_bytes());
+
+        assert_eq!(kernel_target_mismatch(&kernel, Some("aarch64")), None);
+        assert_eq!(kernel_target_mismatch(&kernel, Some("unknown")), None);
+        assert_eq!(
+            kernel_target_mismatch(&kernel, Some("x86_64")).as_deref(),
+            Some("its ELF machine type is 183, but the bootloader expects a kernel for x86_64")
+        );
+        assert_eq!(
+            kernel_target_mismatch(&kernel[..0x13], Some("x86_64")).as_deref(),
+            Some("not an ELF file")
+        );
     }
 }
@@ -0,0 +1,80 @@
+use std::{path::PathBuf, process::Command};
+
+/// The crates that are built with `-Zbuild-std` in place of `cargo xbuild`.
+///
+/// Together with [`BUILD_STD_FEATURES`], this mirrors the sysroot that `cargo xbuild` builds
+/// by default.
+pub const BUILD_STD_CRATES: &str = "core,compiler_builtins";
+/// The `-Zbuild-std-features` that are enabled in place of `cargo xbuild`.
+pub const BUILD_STD_FEATURES: &str = "compiler-builtins-mem";
+
+/// Returns whether the active toolchain supports `cargo build -Zbuild-std`.
+///
+/// This is the case for nightly toolchains that have the `rust-src` component installed.
+pub fn supports_build_std() -> bool {
+    is_nightly() && rust_src_installed()
+}
+
+/// Returns whether the active `rustc` is a nightly (or locally built dev) compiler.
+pub fn is_nightly() -> bool {
+    rustc_release()
+        .map(|release| release.contains("-nightly") || release.contains("-dev"))
+        .unwrap_or(false)
+}
+
+/// Returns the `release` reported by `rustc -vV` (e.g. `1.80.0` or `1.82.0-nightly`).
+///
+/// Returns `None` if `rustc` could not be run.
+pub fn rustc_release() -> Option<String> {
+    rustc_version_info()?
+        .lines()
+        .find_map(|line| line.strip_prefix("release:"))
+        .map(|release| release.trim().to_owned())
+}
+
+/// Returns whether the `rust-src` component is installed for the active toolchain.
+pub fn rust_src_installed() -> bool {
+    sysroot()
+        .map(|sysroot| sysroot.join("lib").join("rustlib").join("src").join("rust"))
+        .map(|path| path.exists())
+        .unwrap_or(false)
+}
+
+/// Returns whether the given name is a target triple that is built into the active `rustc`.
+pub fn is_builtin_target(name: &str) -> bool {
+    rustc_command()
+        .arg("--print")
+        .arg("target-list")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|list| list.lines().any(|target| target.trim() == name))
+        .unwrap_or(false)
+}
+
+fn rustc_version_info() -> Option<String> {
+    let output = rustc_command().arg("-vV").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+fn sysroot() -> Option<PathBuf> {
+    let output = rustc_command()
+        .arg("--print")
+        .arg("sysroot")
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let sysroot = String::from_utf8(output.stdout).ok()?;
+    Some(PathBuf::from(sysroot.trim()))
+}
+
+fn rustc_command() -> Command {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    Command::new(rustc)
+}
@@ -0,0 +1,116 @@
+//! Controls whether the terminal output of bootimage is colored.
+
+use anyhow::{anyhow, Result};
+use std::{
+    io::{self, IsTerminal},
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+static COLOR_CHOICE: AtomicU8 = AtomicU8::new(ColorChoice::Auto as u8);
+
+/// The value of the global `--color` argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Color the output only if it is written to a terminal (the default).
+    Auto = 0,
+    /// Always color the output.
+    Always = 1,
+    /// Never color the output.
+    Never = 2,
+}
+
+impl FromStr for ColorChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            other => Err(anyhow!(
+                "invalid --color value `{}` (expected `always`, `never`, or `auto`)",
+                other
+            )),
+        }
+    }
+}
+
+/// Sets the color choice that is consulted by all colored output.
+pub fn set_color_choice(choice: ColorChoice) {
+    COLOR_CHOICE.store(choice as u8, Ordering::Relaxed);
+}
+
+/// Returns the current color choice.
+pub fn color_choice() -> ColorChoice {
+    match COLOR_CHOICE.load(Ordering::Relaxed) {
+        1 => ColorChoice::Always,
+        2 => ColorChoice::Never,
+        _ => ColorChoice::Auto,
+    }
+}
+
+/// Parses a `--color <when>` or `--color=<when>` argument.
+///
+/// Returns `None` if the given argument is not a `--color` argument. For the
+/// `--color <when>` form, the value is taken from `args`.
+pub fn parse_color_arg<A>(arg: &str, args: &mut A) -> Result<Option<ColorChoice>>
+where
+    A: Iterator<Item = String>,
+{
+    if arg == "--color" {
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow!("--color expects a value"))?;
+        value.parse().map(Some)
+    } else if let Some(value) = arg.strip_prefix("--color=") {
+        value.parse().map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// The output stream that colored text is written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    /// Standard output
+    Stdout,
+    /// Standard error
+    Stderr,
+}
+
+/// The colors supported by [`paint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    /// Red (e.g. for errors and failures)
+    Red,
+    /// Green (e.g. for successes)
+    Green,
+    /// Yellow (e.g. for warnings)
+    Yellow,
+}
+
+/// Returns whether output to the given stream should be colored.
+pub fn enabled(stream: Stream) -> bool {
+    match color_choice() {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => match stream {
+            Stream::Stdout => io::stdout().is_terminal(),
+            Stream::Stderr => io::stderr().is_terminal(),
+        },
+    }
+}
+
+/// Wraps the given text in ANSI color codes if coloring is enabled for the given stream.
+pub fn paint(text: &str, color: Color, stream: Stream) -> String {
+    if !enabled(stream) {
+        return text.to_owned();
+    }
+    let code = match color {
+        Color::Red => 31,
+        Color::Green => 32,
+        Color::Yellow => 33,
+    };
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
@@ -1,7 +1,12 @@
 //! Parses the `package.metadata.bootimage` configuration table
 
 use anyhow::{anyhow, Context, Result};
-use std::path::Path;
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use toml::Value;
 
 /// Represents the `package.metadata.bootimage` configuration table
@@ -18,8 +23,18 @@ pub struct Config {
     pub build_command: Vec<String>,
     /// The run command that is invoked on `bootimage run` or `bootimage runner`
     ///
-    /// The substring "{}" will be replaced with the path to the bootable disk image.
+    /// The substring "{}" will be replaced with the path to the bootable disk image. A
+    /// program starting with `./` or `../` is resolved against the directory of the
+    /// `Cargo.toml`. If no `run-command` is configured, this is the default command for
+    /// `x86_64` (see [`Config::run_command_for_arch`] for other architectures).
     pub run_command: Vec<String>,
+    /// Whether the `run-command` was set in the configuration
+    run_command_configured: bool,
+    /// The architecture of the kernel, which overrides the architecture inferred from the
+    /// target triple or the bootloader target
+    ///
+    /// Used for selecting the default run command and the `llvm-objcopy` arguments.
+    pub arch: Option<Arch>,
     /// Additional arguments passed to the runner for not-test binaries
     ///
     /// Applies to `bootimage run` and `bootimage runner`.
@@ -30,21 +45,691 @@ pub struct Config {
     pub test_args: Option<Vec<String>>,
     /// The timeout for running an test through `bootimage test` or `bootimage runner` in seconds
     pub test_timeout: u32,
+    /// Timeouts in seconds for specific tests that override `test_timeout`
+    ///
+    /// The keys are test names (e.g. `should_panic` for `tests/should_panic.rs`).
+    pub test_timeouts: BTreeMap<String, u32>,
     /// An exit code that should be considered as success for test executables (applies to
     /// `bootimage runner`)
     pub test_success_exit_code: Option<i32>,
+    /// An exit code that should be considered as success for doctest executables
+    ///
+    /// Falls back to `test_success_exit_code` if not set.
+    pub doctest_success_exit_code: Option<i32>,
+    /// An exit code that should be considered as success for non-test executables
+    ///
+    /// If set, this code is reported as `0` and an exit code of `0` is reported as `1`. This
+    /// requires a device that exits QEMU with a status code (e.g. `isa-debug-exit`) in the
+    /// run command.
+    pub run_success_exit_code: Option<i32>,
+    /// Maps QEMU exit codes of test executables to the exit codes that the runner reports
+    ///
+    /// Applies to exit codes that don't match `test_success_exit_code`, which takes
+    /// precedence.
+    pub test_exit_code_remap: BTreeMap<i32, i32>,
+    /// A QEMU device that is added through `-device` for test executables
+    ///
+    /// The device is responsible for exiting QEMU with an exit code that is interpreted
+    /// through `test_success_exit_code` (e.g. `isa-debug-exit,iobase=0xf4,iosize=0x04`).
+    pub test_exit_device: Option<String>,
     /// Whether the `-no-reboot` flag should be passed to test executables
     ///
     /// Defaults to `true`
     pub test_no_reboot: bool,
+    /// The index of the serial port that `--print-serial-on-failure` writes to a file
+    ///
+    /// The serial ports before it are connected to `null`. Defaults to `0`.
+    pub test_serial_index: u32,
+    /// Whether built bootloaders should be cached in `target/bootimage/cache`
+    ///
+    /// The cache is keyed by the kernel binary, the bootloader features, and the bootloader
+    /// target. Defaults to `false`.
+    pub cache: bool,
+    /// Whether the runner skips creating the disk image if the executable and the
+    /// configuration are unchanged since the disk image was last created
+    ///
+    /// Defaults to `false`.
+    pub incremental: bool,
+    /// Path to the OVMF firmware that is used for `bootimage runner --uefi`
+    pub ovmf_path: Option<PathBuf>,
+    /// Path to an initial ramdisk that is passed to QEMU as `-initrd <path>`
+    ///
+    /// Relative paths are resolved against the directory of the `Cargo.toml`.
+    pub initrd: Option<PathBuf>,
+    /// The QEMU debug log items that are passed as `-d <flags>` (e.g. `int,cpu_reset`)
+    pub qemu_debug_flags: Option<String>,
+    /// The file that QEMU writes its debug log to (passed as `-D <path>`)
+    ///
+    /// Relative paths are resolved against the directory of the `Cargo.toml`. The parent
+    /// directory is created if it doesn't exist.
+    pub qemu_debug_log: Option<PathBuf>,
+    /// The working directory for the run command
+    ///
+    /// Relative paths are resolved against the directory of the `Cargo.toml`. Defaults to
+    /// the current directory.
+    pub run_working_dir: Option<PathBuf>,
+    /// Whether to create the disk image in the format of old bootloader versions
+    ///
+    /// If enabled, the image is created by concatenating the `.bootloader` section of the
+    /// bootloader, a kernel info block, and the kernel. Defaults to `false`.
+    pub legacy_image: bool,
+    /// Whether the bootloader is built in the target directory of the kernel instead of
+    /// `target/bootimage/<bootloader>`, so that compiled dependencies can be reused
+    ///
+    /// Defaults to `false`.
+    pub shared_target_dir: bool,
+    /// Whether the debug symbols of the kernel are moved to a separate `.sym` file next to
+    /// the disk image, so that the disk image contains a stripped kernel
+    ///
+    /// Defaults to `false`.
+    pub strip_symbols: bool,
+    /// Whether the created disk image is checked for a boot signature (`0x55 0xAA` at
+    /// offset 510)
+    ///
+    /// Only applies to `x86_64` images, which are booted through the BIOS. Defaults to
+    /// `true`.
+    pub check_boot_signature: bool,
+    /// Path to the `Cargo.toml` of the bootloader
+    ///
+    /// If set, the bootloader is not searched in the dependencies of the kernel. Relative
+    /// paths are resolved against the directory of the `Cargo.toml`.
+    pub bootloader_manifest_path: Option<PathBuf>,
+    /// The cargo executable that is used for building the bootloader
+    ///
+    /// Relative paths with more than one component are resolved against the directory of
+    /// the `Cargo.toml`; plain names are looked up in `PATH`. If unset, the `CARGO`
+    /// environment variable or `cargo` is used, like for the kernel build.
+    pub bootloader_cargo: Option<PathBuf>,
+    /// Whether the bootloader is built with `--locked`
+    ///
+    /// This keeps the dependency versions of the bootloader fixed, which requires that the
+    /// bootloader crate has a `Cargo.lock` file. Defaults to `false`.
+    pub bootloader_locked: bool,
+    /// Whether the `binary` feature is enabled for the bootloader build if the bootloader
+    /// declares it
+    ///
+    /// Newer bootloader versions don't need this feature. Defaults to `true`.
+    pub bootloader_binary_feature: bool,
+    /// Whether `cargo xbuild` is replaced with `cargo build -Zbuild-std` for the kernel and
+    /// bootloader builds if the active toolchain supports it
+    ///
+    /// Defaults to `true`.
+    pub auto_build_std: bool,
+    /// The name of the environment variable that passes the kernel executable path to the
+    /// bootloader build
+    ///
+    /// Defaults to `KERNEL`.
+    pub kernel_env_var: String,
+    /// The name of the environment variable that passes the kernel `Cargo.toml` path to the
+    /// bootloader build
+    ///
+    /// Defaults to `KERNEL_MANIFEST`.
+    pub kernel_manifest_env_var: String,
+    /// Additional images that are created from the bootable disk image with different padding
+    pub additional_images: Vec<AdditionalImage>,
+    /// A command that is run on the finished disk image
+    ///
+    /// The substring "{bootimage}" is replaced with the path to the disk image.
+    pub post_image_command: Option<Vec<String>>,
+    /// A command that is run in the directory of the `Cargo.toml` before building the kernel
+    /// (e.g. for generating source files)
+    pub pre_build_command: Option<Vec<String>>,
+    /// Path to a linker script that is passed to the kernel build via `-Clink-arg=-T<path>`
+    ///
+    /// Relative paths are resolved against the directory of the `Cargo.toml`.
+    pub kernel_linker_script: Option<PathBuf>,
+    /// Whether to attach a virtio-rng device (`-device virtio-rng-pci`) to the run command
+    ///
+    /// Defaults to `false`.
+    pub enable_rng: bool,
+    /// Whether only the last occurrence of single-valued QEMU options (`-display`, `-m`,
+    /// `-cpu`) is kept in the run command
+    ///
+    /// Defaults to `false`.
+    pub dedup_qemu_args: bool,
+    /// The time in seconds that a timed out test is given to exit after it was asked to
+    /// terminate, before it is killed forcefully
+    ///
+    /// Defaults to 2 seconds.
+    pub kill_grace_period: u32,
+    /// Milliseconds to wait before launching QEMU (e.g. for host-side setup to finish)
+    ///
+    /// Defaults to `0`.
+    pub qemu_start_delay: u64,
+    /// A directory with custom target JSON files
+    ///
+    /// A `--target X` argument for the kernel build that is neither a JSON path nor a
+    /// built-in target is resolved to `X.json` in this directory. Relative paths are
+    /// resolved against the directory of the `Cargo.toml`.
+    pub target_json_dir: Option<PathBuf>,
+    /// How often `cargo metadata` is retried after a network or registry failure
+    ///
+    /// Defaults to 2 retries.
+    pub metadata_retries: u32,
+    /// Build the kernel and the bootloader in a single cargo invocation with JSON output
+    ///
+    /// The compiler diagnostics are then printed from the JSON messages instead of running a
+    /// second build pass for retrieving the executable paths.
+    pub single_pass_build: bool,
+    /// The number of zero bytes that are placed in front of the bootloader in the disk image
+    ///
+    /// Must be a multiple of the block size (512 bytes). Does not apply to legacy images.
+    pub image_start_offset: u64,
+    /// The partition table that the disk image is additionally wrapped in
+    ///
+    /// For [`PartitionTable::Mbr`], an `.mbr.img` file is created next to the disk image.
+    /// Defaults to [`PartitionTable::None`].
+    pub partition_table: PartitionTable,
+    /// The format that the disk image is additionally converted to through `qemu-img`
+    ///
+    /// For [`ImageType::Vhd`] and [`ImageType::Vmdk`], a `.vhd` or `.vmdk` file is created next
+    /// to the raw disk image. Defaults to [`ImageType::Raw`].
+    pub image_type: ImageType,
+    /// How the kernel is booted
+    ///
+    /// With [`BootMode::DirectKernel`], no disk image is created and the kernel executable is
+    /// passed to QEMU through `-kernel` instead. Defaults to [`BootMode::DiskImage`].
+    pub boot_mode: BootMode,
+    /// The first sector (LBA) of the partition in the partitioned image
+    ///
+    /// Defaults to `2048` (i.e. 1MiB).
+    pub partition_start_lba: u32,
+    /// Target features for the kernel build (e.g. `["+sse", "-mmx"]`), passed as
+    /// `-Ctarget-feature`
+    ///
+    /// This does not affect the bootloader build.
+    pub kernel_target_features: Vec<String>,
+    /// Host ports that are forwarded to the guest through a QEMU user-mode network device
+    pub port_forwards: Vec<PortForward>,
+    /// The QEMU network device model that is used for `port-forward` (e.g. `virtio-net-pci`)
+    ///
+    /// Defaults to `e1000`.
+    pub qemu_nic: String,
+    /// The QEMU accelerator that is passed as `-accel` to the run command
+    pub accel: Option<Accel>,
+}
+
+/// The partition table that the disk image is wrapped in (`partition-table` key).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionTable {
+    /// Don't create a partitioned image (`"none"`)
+    None,
+    /// Create an image with an MBR partition table (`"mbr"`)
+    Mbr,
+}
+
+/// How the kernel is booted (`boot-mode` key).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMode {
+    /// Combine the kernel with the bootloader into a bootable disk image (`"disk-image"`)
+    DiskImage,
+    /// Boot the kernel executable directly through QEMU's `-kernel` option, e.g. for
+    /// multiboot kernels (`"direct-kernel"`)
+    ///
+    /// The run command can reference the kernel executable as `{kernel_elf}`.
+    DirectKernel,
+}
+
+/// The format of the converted disk image (`image-type` key).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageType {
+    /// Only create the raw disk image (`"raw"`)
+    Raw,
+    /// Additionally create a VHD image, e.g. for Hyper-V (`"vhd"`)
+    Vhd,
+    /// Additionally create a VMDK image, e.g. for VirtualBox or VMware (`"vmdk"`)
+    Vmdk,
+}
+
+impl ImageType {
+    /// Returns the name of the image type in the configuration.
+    pub fn name(self) -> &'static str {
+        match self {
+            ImageType::Raw => "raw",
+            ImageType::Vhd => "vhd",
+            ImageType::Vmdk => "vmdk",
+        }
+    }
+
+    /// Returns the output format name of `qemu-img convert -O` for this image type.
+    pub fn qemu_img_format(self) -> &'static str {
+        match self {
+            ImageType::Raw => "raw",
+            ImageType::Vhd => "vpc",
+            ImageType::Vmdk => "vmdk",
+        }
+    }
+}
+
+/// An additional image that is created by copying and re-padding the bootable disk image.
+///
+/// Configured through the `additional-images` key, e.g.
+/// `additional-images = [{ suffix = ".img", min-size = "64M", block-size = 512 }]`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct AdditionalImage {
+    /// The suffix that replaces the `.bin` extension of the disk image
+    pub suffix: String,
+    /// The minimum size of the image in bytes
+    pub min_size: u64,
+    /// The image is padded to a multiple of this block size (in bytes)
+    pub block_size: u64,
+}
+
+/// The QEMU accelerator (`accel` key).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Accel {
+    /// The portable TCG emulator (`"tcg"`)
+    Tcg,
+    /// The Linux Kernel-based Virtual Machine (`"kvm"`)
+    Kvm,
+    /// The macOS Hypervisor.framework (`"hvf"`)
+    Hvf,
+    /// The Windows Hypervisor Platform (`"whpx"`)
+    Whpx,
+    /// The accelerator of the host platform with a fallback to TCG (`"auto"`)
+    Auto,
+}
+
+impl Accel {
+    /// Returns the name of the accelerator as used in the configuration.
+    pub fn name(self) -> &'static str {
+        match self {
+            Accel::Tcg => "tcg",
+            Accel::Kvm => "kvm",
+            Accel::Hvf => "hvf",
+            Accel::Whpx => "whpx",
+            Accel::Auto => "auto",
+        }
+    }
+}
+
+/// A host port that is forwarded to a guest port.
+///
+/// Configured through the `port-forward` key, e.g.
+/// `port-forward = [{ host = 8080, guest = 80, protocol = "tcp" }]`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PortForward {
+    /// The port on the host
+    pub host: u16,
+    /// The port in the guest
+    pub guest: u16,
+    /// The protocol (`"tcp"` or `"udp"`)
+    pub protocol: String,
+}
+
+impl PortForward {
+    /// Returns the `hostfwd` option for QEMU's user-mode `-netdev`, e.g. `hostfwd=tcp::8080-:80`.
+    pub fn hostfwd(&self) -> String {
+        format!("hostfwd={}::{}-:{}", self.protocol, self.host, self.guest)
+    }
+}
+
+/// A CPU architecture with built-in defaults (`arch` key or `--arch` argument).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    /// `x86_64`
+    X86_64,
+    /// `aarch64`
+    Aarch64,
+    /// `riscv64`
+    Riscv64,
+}
+
+impl Arch {
+    /// Infers the architecture from the first component of the given target triple (or
+    /// custom target name).
+    ///
+    /// All `riscv64*` variants (e.g. `riscv64gc`) are treated as `riscv64`. Returns `None`
+    /// for unknown architectures.
+    pub fn from_target_triple(target_triple: &str) -> Option<Self> {
+        match target_triple.split('-').next()? {
+            "x86_64" => Some(Arch::X86_64),
+            "aarch64" => Some(Arch::Aarch64),
+            arch if arch.starts_with("riscv64") => Some(Arch::Riscv64),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the architecture as used in target triples.
+    pub fn name(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+            Arch::Riscv64 => "riscv64",
+        }
+    }
+
+    /// Returns the default run command for the architecture.
+    pub fn default_run_command(self) -> Vec<String> {
+        let command: &[&str] = match self {
+            Arch::X86_64 => &["qemu-system-x86_64", "-drive", "format=raw,file={}"],
+            Arch::Aarch64 => &[
+                "qemu-system-aarch64",
+                "-machine",
+                "virt",
+                "-cpu",
+                "cortex-a57",
+                "-drive",
+                "if=none,format=raw,file={},id=hd0",
+                "-device",
+                "virtio-blk-device,drive=hd0",
+            ],
+            Arch::Riscv64 => &[
+                "qemu-system-riscv64",
+                "-machine",
+                "virt",
+                "-drive",
+                "if=none,format=raw,file={},id=hd0",
+                "-device",
+                "virtio-blk-device,drive=hd0",
+            ],
+        };
+        command.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// Returns the default run command for booting the kernel executable directly through
+    /// QEMU's `-kernel` option (see [`BootMode::DirectKernel`]).
+    pub fn direct_kernel_run_command(self) -> Vec<String> {
+        let command: &[&str] = match self {
+            Arch::X86_64 => &["qemu-system-x86_64", "-kernel", "{kernel_elf}"],
+            Arch::Aarch64 => &[
+                "qemu-system-aarch64",
+                "-machine",
+                "virt",
+                "-cpu",
+                "cortex-a57",
+                "-kernel",
+                "{kernel_elf}",
+            ],
+            Arch::Riscv64 => &[
+                "qemu-system-riscv64",
+                "-machine",
+                "virt",
+                "-kernel",
+                "{kernel_elf}",
+            ],
+        };
+        command.iter().map(|arg| arg.to_string()).collect()
+    }
+}
+
+impl FromStr for Arch {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "x86_64" => Ok(Arch::X86_64),
+            "aarch64" => Ok(Arch::Aarch64),
+            "riscv64" => Ok(Arch::Riscv64),
+            other => Err(anyhow!(
+                "invalid architecture `{}` (expected `x86_64`, `aarch64`, or `riscv64`)",
+                other
+            )),
+        }
+    }
+}
+
+impl Config {
+    /// Returns the architecture of an executable built for the given target triple.
+    ///
+    /// The configured `arch` takes precedence over the architecture inferred from the
+    /// target triple. Defaults to `x86_64`.
+    pub fn arch_for_target(&self, target_triple: Option<&str>) -> Arch {
+        self.arch
+            .or_else(|| target_triple.and_then(Arch::from_target_triple))
+            .unwrap_or(Arch::X86_64)
+    }
+
+    /// Returns the run command for an executable of the given architecture.
+    ///
+    /// If no `run-command` is configured, the default command of the architecture is
+    /// returned.
+    pub fn run_command_for_arch(&self, arch: Arch) -> Vec<String> {
+        if self.run_command_configured {
+            self.run_command.clone()
+        } else if self.boot_mode == BootMode::DirectKernel {
+            arch.direct_kernel_run_command()
+        } else {
+            arch.default_run_command()
+        }
+    }
+
+    /// Returns the timeout in seconds for the test with the given name.
+    ///
+    /// Uses the matching `test-timeouts` entry if there is one and falls back to
+    /// `test-timeout` otherwise. Dashes and underscores are treated as equivalent since
+    /// cargo replaces dashes in the names of test executables.
+    pub fn test_timeout_for(&self, test_name: &str) -> u32 {
+        let normalize = |name: &str| name.replace('-', "_");
+        let test_name = normalize(test_name);
+        self.test_timeouts
+            .iter()
+            .find(|(name, _)| normalize(name) == test_name)
+            .map(|(_, &timeout)| timeout)
+            .unwrap_or(self.test_timeout)
+    }
+
+    /// Formats the effective configuration (including defaults) as a
+    /// `[package.metadata.bootimage]` TOML table.
+    ///
+    /// Unset optional keys are omitted since TOML has no null value.
+    pub fn to_toml(&self) -> String {
+        fn strings(values: &[String]) -> Value {
+            Value::Array(values.iter().cloned().map(Value::String).collect())
+        }
+        fn path(path: &Path) -> Value {
+            Value::String(path.display().to_string())
+        }
+
+        let mut table = toml::value::Table::new();
+        let mut insert = |key: &str, value: Value| {
+            table.insert(key.to_owned(), value);
+        };
+        insert("build-command", strings(&self.build_command));
+        insert("run-command", strings(&self.run_command));
+        if let Some(args) = &self.run_args {
+            insert("run-args", strings(args));
+        }
+        if let Some(args) = &self.test_args {
+            insert("test-args", strings(args));
+        }
+        insert("test-timeout", Value::Integer(self.test_timeout.into()));
+        insert(
+            "test-timeouts",
+            Value::Table(
+                self.test_timeouts
+                    .iter()
+                    .map(|(name, &timeout)| (name.clone(), Value::Integer(timeout.into())))
+                    .collect(),
+            ),
+        );
+        if let Some(code) = self.test_success_exit_code {
+            insert("test-success-exit-code", Value::Integer(code.into()));
+        }
+        if let Some(code) = self.doctest_success_exit_code {
+            insert("doctest-success-exit-code", Value::Integer(code.into()));
+        }
+        if let Some(code) = self.run_success_exit_code {
+            insert("run-success-exit-code", Value::Integer(code.into()));
+        }
+        insert(
+            "test-exit-code-remap",
+            Value::Table(
+                self.test_exit_code_remap
+                    .iter()
+                    .map(|(from, &to)| (from.to_string(), Value::Integer(to.into())))
+                    .collect(),
+            ),
+        );
+        if let Some(device) = &self.test_exit_device {
+            insert("test-exit-device", Value::String(device.clone()));
+        }
+        insert("test-no-reboot", Value::Boolean(self.test_no_reboot));
+        insert(
+            "test-serial-index",
+            Value::Integer(self.test_serial_index.into()),
+        );
+        insert("cache", Value::Boolean(self.cache));
+        insert("incremental", Value::Boolean(self.incremental));
+        if let Some(ovmf_path) = &self.ovmf_path {
+            insert("ovmf-path", path(ovmf_path));
+        }
+        if let Some(initrd) = &self.initrd {
+            insert("initrd", path(initrd));
+        }
+        if let Some(flags) = &self.qemu_debug_flags {
+            insert("qemu-debug-flags", Value::String(flags.clone()));
+        }
+        if let Some(log) = &self.qemu_debug_log {
+            insert("qemu-debug-log", path(log));
+        }
+        if let Some(dir) = &self.run_working_dir {
+            insert("run-working-dir", path(dir));
+        }
+        insert("legacy-image", Value::Boolean(self.legacy_image));
+        insert("shared-target-dir", Value::Boolean(self.shared_target_dir));
+        insert("strip-symbols", Value::Boolean(self.strip_symbols));
+        insert(
+            "check-boot-signature",
+            Value::Boolean(self.check_boot_signature),
+        );
+        if let Some(manifest_path) = &self.bootloader_manifest_path {
+            insert("bootloader-manifest-path", path(manifest_path));
+        }
+        if let Some(cargo) = &self.bootloader_cargo {
+            insert("bootloader-cargo", path(cargo));
+        }
+        insert("bootloader-locked", Value::Boolean(self.bootloader_locked));
+        insert(
+            "bootloader-binary-feature",
+            Value::Boolean(self.bootloader_binary_feature),
+        );
+        insert("auto-build-std", Value::Boolean(self.auto_build_std));
+        insert("kernel-env-var", Value::String(self.kernel_env_var.clone()));
+        insert(
+            "kernel-manifest-env-var",
+            Value::String(self.kernel_manifest_env_var.clone()),
+        );
+        insert(
+            "additional-images",
+            Value::Array(
+                self.additional_images
+                    .iter()
+                    .map(|image| {
+                        let mut table = toml::value::Table::new();
+                        table.insert("suffix".into(), Value::String(image.suffix.clone()));
+                        table.insert("min-size".into(), Value::Integer(image.min_size as i64));
+                        table.insert("block-size".into(), Value::Integer(image.block_size as i64));
+                        Value::Table(table)
+                    })
+                    .collect(),
+            ),
+        );
+        if let Some(command) = &self.post_image_command {
+            insert("post-image-command", strings(command));
+        }
+        if let Some(command) = &self.pre_build_command {
+            insert("pre-build-command", strings(command));
+        }
+        if let Some(linker_script) = &self.kernel_linker_script {
+            insert("kernel-linker-script", path(linker_script));
+        }
+        insert(
+            "kernel-target-features",
+            strings(&self.kernel_target_features),
+        );
+        insert("enable-rng", Value::Boolean(self.enable_rng));
+        insert("dedup-qemu-args", Value::Boolean(self.dedup_qemu_args));
+        insert(
+            "kill-grace-period",
+            Value::Integer(self.kill_grace_period.into()),
+        );
+        insert(
+            "qemu-start-delay",
+            Value::Integer(self.qemu_start_delay as i64),
+        );
+        if let Some(dir) = &self.target_json_dir {
+            insert("target-json-dir", path(dir));
+        }
+        insert(
+            "metadata-retries",
+            Value::Integer(self.metadata_retries.into()),
+        );
+        insert("single-pass-build", Value::Boolean(self.single_pass_build));
+        insert(
+            "image-start-offset",
+            Value::Integer(self.image_start_offset as i64),
+        );
+        let partition_table = match self.partition_table {
+            PartitionTable::None => "none",
+            PartitionTable::Mbr => "mbr",
+        };
+        insert("partition-table", Value::String(partition_table.into()));
+        insert("image-type", Value::String(self.image_type.name().into()));
+        let boot_mode = match self.boot_mode {
+            BootMode::DiskImage => "disk-image",
+            BootMode::DirectKernel => "direct-kernel",
+        };
+        insert("boot-mode", Value::String(boot_mode.into()));
+        if let Some(arch) = self.arch {
+            insert("arch", Value::String(arch.name().into()));
+        }
+        insert(
+            "partition-start-lba",
+            Value::Integer(self.partition_start_lba.into()),
+        );
+        insert(
+            "port-forward",
+            Value::Array(
+                self.port_forwards
+                    .iter()
+                    .map(|forward| {
+                        let mut table = toml::value::Table::new();
+                        table.insert("host".into(), Value::Integer(forward.host.into()));
+                        table.insert("guest".into(), Value::Integer(forward.guest.into()));
+                        table.insert("protocol".into(), Value::String(forward.protocol.clone()));
+                        Value::Table(table)
+                    })
+                    .collect(),
+            ),
+        );
+        insert("qemu-nic", Value::String(self.qemu_nic.clone()));
+        if let Some(accel) = self.accel {
+            insert("accel", Value::String(accel.name().into()));
+        }
+
+        let mut metadata = toml::value::Table::new();
+        metadata.insert("bootimage".into(), Value::Table(table));
+        let mut package = toml::value::Table::new();
+        package.insert("metadata".into(), Value::Table(metadata));
+        let mut root = toml::value::Table::new();
+        root.insert("package".into(), Value::Table(package));
+        Value::Table(root).to_string()
+    }
 }
 
 /// Reads the configuration from a `package.metadata.bootimage` in the given Cargo.toml.
+///
+/// Keys that are not set in the Cargo.toml are taken from the user-level configuration file
+/// (see [`global_config_path`]), if it exists. Set the `BOOTIMAGE_NO_USER_CONFIG`
+/// environment variable to `1` to ignore the user-level file.
 pub fn read_config(manifest_path: &Path) -> Result<Config> {
-    read_config_inner(manifest_path).context("Failed to read bootimage configuration")
+    read_config_inner(manifest_path, None).context("Failed to read bootimage configuration")
+}
+
+/// Reads the configuration for the given cargo profile (e.g. `dev` or `release`).
+///
+/// The keys of a `package.metadata.bootimage.profile.<profile>` table override the keys of
+/// the `package.metadata.bootimage` table. The same applies to `profile.<profile>` tables in
+/// the user-level configuration file, whose keys are overridden by the project keys.
+pub fn read_config_for_profile(manifest_path: &Path, profile: &str) -> Result<Config> {
+    read_config_inner(manifest_path, Some(profile))
+        .context("Failed to read bootimage configuration")
 }
 
-fn read_config_inner(manifest_path: &Path) -> Result<Config> {
+fn read_config_inner(manifest_path: &Path, profile: Option<&str>) -> Result<Config> {
     use std::{fs::File, io::Read};
     let cargo_toml: Value = {
         let mut content = String::new();
@@ -62,17 +747,25 @@ fn read_config_inner(manifest_path: &Path) -> Result<Config> {
         .and_then(|table| table.get("metadata"))
         .and_then(|table| table.get("bootimage"));
     let metadata = match metadata {
-        None => {
-            return Ok(ConfigBuilder::default().into());
-        }
-        Some(metadata) => metadata
-            .as_table()
-            .ok_or_else(|| anyhow!("Bootimage configuration invalid: {:?}", metadata))?,
+        None => toml::value::Table::new(),
+        Some(metadata) => merge_profile(
+            metadata
+                .as_table()
+                .ok_or_else(|| anyhow!("Bootimage configuration invalid: {:?}", metadata))?,
+            profile,
+        )?,
     };
+    // project keys take precedence over the user-level configuration
+    let mut merged = read_global_config(profile)?;
+    merged.extend(metadata);
+    let metadata = merged;
 
+    let manifest_dir = manifest_path
+        .parent()
+        .ok_or_else(|| anyhow!("Cargo.toml path has no parent directory"))?;
     let mut config = ConfigBuilder::default();
 
-    for (key, value) in metadata {
+    for (key, value) in &metadata {
         match (key.as_str(), value.clone()) {
             ("test-timeout", Value::Integer(timeout)) if timeout.is_negative() => {
                 return Err(anyhow!("test-timeout must not be negative"))
@@ -80,24 +773,251 @@ fn read_config_inner(manifest_path: &Path) -> Result<Config> {
             ("test-timeout", Value::Integer(timeout)) => {
                 config.test_timeout = Some(timeout as u32);
             }
+            ("qemu-start-delay", Value::Integer(delay)) if delay.is_negative() => {
+                return Err(anyhow!("qemu-start-delay must not be negative"))
+            }
+            ("qemu-start-delay", Value::Integer(delay)) => {
+                config.qemu_start_delay = Some(delay as u64);
+            }
+            ("kill-grace-period", Value::Integer(period)) if period.is_negative() => {
+                return Err(anyhow!("kill-grace-period must not be negative"))
+            }
+            ("kill-grace-period", Value::Integer(period)) => {
+                config.kill_grace_period = Some(period as u32);
+            }
+            ("metadata-retries", Value::Integer(retries)) if retries.is_negative() => {
+                return Err(anyhow!("metadata-retries must not be negative"))
+            }
+            ("metadata-retries", Value::Integer(retries)) => {
+                config.metadata_retries = Some(retries as u32);
+            }
+            ("single-pass-build", Value::Boolean(single_pass)) => {
+                config.single_pass_build = Some(single_pass);
+            }
+            ("test-timeouts", Value::Table(table)) => {
+                let mut timeouts = BTreeMap::new();
+                for (name, value) in table {
+                    match value {
+                        Value::Integer(timeout) if !timeout.is_negative() => {
+                            timeouts.insert(name, timeout as u32);
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                                "test-timeouts.{} must be a non-negative integer",
+                                name
+                            ))
+                        }
+                    }
+                }
+                config.test_timeouts = Some(timeouts);
+            }
             ("test-success-exit-code", Value::Integer(exit_code)) => {
                 config.test_success_exit_code = Some(exit_code as i32);
             }
+            ("doctest-success-exit-code", Value::Integer(exit_code)) => {
+                config.doctest_success_exit_code = Some(exit_code as i32);
+            }
+            ("run-success-exit-code", Value::Integer(exit_code)) => {
+                config.run_success_exit_code = Some(exit_code as i32);
+            }
+            ("test-exit-code-remap", Value::Table(table)) => {
+                let mut remap = BTreeMap::new();
+                for (from, to) in table {
+                    let err = || {
+                        anyhow!(
+                            "test-exit-code-remap must map integer exit codes to integers, \
+                            found `{} = {}`",
+                            from,
+                            to
+                        )
+                    };
+                    let from_code = from.parse::<i32>().map_err(|_| err())?;
+                    let to_code = to.as_integer().ok_or_else(err)?;
+                    remap.insert(from_code, to_code as i32);
+                }
+                config.test_exit_code_remap = Some(remap);
+            }
             ("build-command", Value::Array(array)) => {
-                config.build_command = Some(parse_string_array(array, "build-command")?);
+                config.build_command = Some(parse_expanded_string_array(array, "build-command")?);
             }
             ("run-command", Value::Array(array)) => {
-                config.run_command = Some(parse_string_array(array, "run-command")?);
+                let mut run_command = parse_expanded_string_array(array, "run-command")?;
+                // resolve project-local scripts independently of the current directory
+                if let Some(program) = run_command.first_mut() {
+                    if program.starts_with("./") || program.starts_with("../") {
+                        *program = manifest_dir.join(&program).display().to_string();
+                    }
+                }
+                config.run_command = Some(run_command);
             }
             ("run-args", Value::Array(array)) => {
-                config.run_args = Some(parse_string_array(array, "run-args")?);
+                config.run_args = Some(parse_expanded_string_array(array, "run-args")?);
             }
             ("test-args", Value::Array(array)) => {
-                config.test_args = Some(parse_string_array(array, "test-args")?);
+                config.test_args = Some(parse_expanded_string_array(array, "test-args")?);
+            }
+            ("test-exit-device", Value::String(device)) => {
+                config.test_exit_device = Some(device);
             }
             ("test-no-reboot", Value::Boolean(no_reboot)) => {
                 config.test_no_reboot = Some(no_reboot);
             }
+            ("test-serial-index", Value::Integer(index)) if !(0..=3).contains(&index) => {
+                return Err(anyhow!("test-serial-index must be between 0 and 3"))
+            }
+            ("test-serial-index", Value::Integer(index)) => {
+                config.test_serial_index = Some(index as u32);
+            }
+            ("cache", Value::Boolean(cache)) => {
+                config.cache = Some(cache);
+            }
+            ("incremental", Value::Boolean(incremental)) => {
+                config.incremental = Some(incremental);
+            }
+            ("ovmf-path", Value::String(path)) => {
+                config.ovmf_path = Some(PathBuf::from(path));
+            }
+            ("initrd", Value::String(path)) => {
+                config.initrd = Some(manifest_dir.join(path));
+            }
+            ("qemu-debug-flags", Value::String(flags)) => {
+                config.qemu_debug_flags = Some(flags);
+            }
+            ("qemu-debug-log", Value::String(path)) => {
+                config.qemu_debug_log = Some(manifest_dir.join(path));
+            }
+            ("run-working-dir", Value::String(path)) => {
+                config.run_working_dir = Some(manifest_dir.join(path));
+            }
+            ("shared-target-dir", Value::Boolean(shared)) => {
+                config.shared_target_dir = Some(shared);
+            }
+            ("strip-symbols", Value::Boolean(strip)) => {
+                config.strip_symbols = Some(strip);
+            }
+            ("check-boot-signature", Value::Boolean(check)) => {
+                config.check_boot_signature = Some(check);
+            }
+            ("legacy-image", Value::Boolean(legacy_image)) => {
+                config.legacy_image = Some(legacy_image);
+            }
+            ("bootloader-manifest-path", Value::String(path)) => {
+                config.bootloader_manifest_path = Some(manifest_dir.join(path));
+            }
+            ("kernel-env-var", Value::String(name)) => {
+                config.kernel_env_var = Some(name);
+            }
+            ("kernel-manifest-env-var", Value::String(name)) => {
+                config.kernel_manifest_env_var = Some(name);
+            }
+            ("bootloader-cargo", Value::String(path)) => {
+                let path = PathBuf::from(path);
+                config.bootloader_cargo = Some(if path.components().count() > 1 {
+                    manifest_dir.join(path)
+                } else {
+                    path
+                });
+            }
+            ("bootloader-locked", Value::Boolean(locked)) => {
+                config.bootloader_locked = Some(locked);
+            }
+            ("bootloader-binary-feature", Value::Boolean(enable)) => {
+                config.bootloader_binary_feature = Some(enable);
+            }
+            ("auto-build-std", Value::Boolean(enable)) => {
+                config.auto_build_std = Some(enable);
+            }
+            ("post-image-command", Value::Array(array)) => {
+                config.post_image_command = Some(parse_string_array(array, "post-image-command")?);
+            }
+            ("pre-build-command", Value::Array(array)) => {
+                config.pre_build_command = Some(parse_string_array(array, "pre-build-command")?);
+            }
+            ("kernel-target-features", Value::Array(array)) => {
+                config.kernel_target_features =
+                    Some(parse_string_array(array, "kernel-target-features")?);
+            }
+            ("dedup-qemu-args", Value::Boolean(dedup)) => {
+                config.dedup_qemu_args = Some(dedup);
+            }
+            ("enable-rng", Value::Boolean(enable)) => {
+                config.enable_rng = Some(enable);
+            }
+            ("target-json-dir", Value::String(path)) => {
+                config.target_json_dir = Some(manifest_dir.join(path));
+            }
+            ("arch", Value::String(arch)) => {
+                config.arch = Some(arch.parse()?);
+            }
+            ("boot-mode", Value::String(mode)) => {
+                config.boot_mode = Some(match mode.as_str() {
+                    "disk-image" => BootMode::DiskImage,
+                    "direct-kernel" => BootMode::DirectKernel,
+                    _ => return Err(anyhow!("boot-mode must be `disk-image` or `direct-kernel`")),
+                });
+            }
+            ("image-type", Value::String(image_type)) => {
+                config.image_type = Some(match image_type.as_str() {
+                    "raw" => ImageType::Raw,
+                    "vhd" => ImageType::Vhd,
+                    "vmdk" => ImageType::Vmdk,
+                    _ => return Err(anyhow!("image-type must be `raw`, `vhd`, or `vmdk`")),
+                });
+            }
+            ("partition-table", Value::String(table)) => {
+                config.partition_table = Some(match table.as_str() {
+                    "none" => PartitionTable::None,
+                    "mbr" => PartitionTable::Mbr,
+                    _ => return Err(anyhow!("partition-table must be `none` or `mbr`")),
+                });
+            }
+            ("partition-start-lba", Value::Integer(lba))
+                if !(1..=u32::MAX.into()).contains(&lba) =>
+            {
+                return Err(anyhow!(
+                    "partition-start-lba must be a positive 32-bit integer"
+                ))
+            }
+            ("partition-start-lba", Value::Integer(lba)) => {
+                config.partition_start_lba = Some(lba as u32);
+            }
+            ("image-start-offset", value) => {
+                config.image_start_offset = Some(parse_size(&value, "image-start-offset")?);
+            }
+            ("kernel-linker-script", Value::String(path)) => {
+                config.kernel_linker_script = Some(manifest_dir.join(path));
+            }
+            ("additional-images", Value::Array(array)) => {
+                let images = array
+                    .into_iter()
+                    .map(parse_additional_image)
+                    .collect::<Result<_>>()?;
+                config.additional_images = Some(images);
+            }
+            ("port-forward", Value::Array(array)) => {
+                let forwards = array
+                    .into_iter()
+                    .map(parse_port_forward)
+                    .collect::<Result<_>>()?;
+                config.port_forwards = Some(forwards);
+            }
+            ("qemu-nic", Value::String(nic)) => {
+                config.qemu_nic = Some(nic);
+            }
+            ("accel", Value::String(accel)) => {
+                config.accel = Some(match accel.as_str() {
+                    "tcg" => Accel::Tcg,
+                    "kvm" => Accel::Kvm,
+                    "hvf" => Accel::Hvf,
+                    "whpx" => Accel::Whpx,
+                    "auto" => Accel::Auto,
+                    _ => {
+                        return Err(anyhow!(
+                            "accel must be `tcg`, `kvm`, `hvf`, `whpx`, or `auto`"
+                        ))
+                    }
+                });
+            }
             (key, value) => {
                 return Err(anyhow!(
                     "unexpected `package.metadata.bootimage` \
@@ -111,6 +1031,117 @@ fn read_config_inner(manifest_path: &Path) -> Result<Config> {
     Ok(config.into())
 }
 
+/// Returns the path of the user-level configuration file.
+///
+/// This is `bootimage/config.toml` in `$XDG_CONFIG_HOME` (defaulting to `~/.config`) or in
+/// `%APPDATA%` on Windows.
+pub fn global_config_path() -> Option<PathBuf> {
+    let config_dir = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+    };
+    Some(config_dir?.join("bootimage").join("config.toml"))
+}
+
+/// Reads the keys of the user-level configuration file with the given profile applied.
+///
+/// The file contains the same keys as the `package.metadata.bootimage` table at its top
+/// level. Returns an empty table if the file does not exist or if the
+/// `BOOTIMAGE_NO_USER_CONFIG` environment variable is set to `1`.
+fn read_global_config(profile: Option<&str>) -> Result<toml::value::Table> {
+    if env::var_os("BOOTIMAGE_NO_USER_CONFIG").is_some_and(|v| v == "1") {
+        return Ok(toml::value::Table::new());
+    }
+    let path = match global_config_path() {
+        Some(path) if path.exists() => path,
+        _ => return Ok(toml::value::Table::new()),
+    };
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read `{}`", path.display()))?;
+    let table = match content
+        .parse::<Value>()
+        .with_context(|| format!("Failed to parse `{}`", path.display()))?
+    {
+        Value::Table(table) => table,
+        other => return Err(anyhow!("Bootimage configuration invalid: {:?}", other)),
+    };
+    merge_profile(&table, profile).with_context(|| format!("Invalid `{}`", path.display()))
+}
+
+/// Removes the `profile` table from the given metadata and applies the keys of the given
+/// profile's table on top.
+fn merge_profile(
+    metadata: &toml::value::Table,
+    profile: Option<&str>,
+) -> Result<toml::value::Table> {
+    let mut merged = metadata.clone();
+    let profiles = match merged.remove("profile") {
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => return Err(anyhow!("profile must be a table of profile tables")),
+        None => return Ok(merged),
+    };
+    if let Some(overrides) = profile.and_then(|profile| profiles.get(profile)) {
+        let overrides = overrides
+            .as_table()
+            .ok_or_else(|| anyhow!("profile.{} must be a table", profile.unwrap_or_default()))?;
+        merged.extend(overrides.clone());
+    }
+    Ok(merged)
+}
+
+/// Parses a string array and expands environment variables in its entries (see [`expand_env`]).
+fn parse_expanded_string_array(array: Vec<Value>, prop_name: &str) -> Result<Vec<String>> {
+    parse_string_array(array, prop_name)?
+        .iter()
+        .map(|s| expand_env(s).with_context(|| format!("invalid entry in {}", prop_name)))
+        .collect()
+}
+
+/// Expands `${VAR}` and `${VAR:-default}` references to environment variables.
+///
+/// The default is used if the variable is not set or empty. Undefined variables without a
+/// default are an error. A literal `$` can be written as `$$`; other `$` characters are
+/// kept as they are.
+fn expand_env(s: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(index) = rest.find('$') {
+        expanded.push_str(&rest[..index]);
+        rest = &rest[index..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            expanded.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| anyhow!("unterminated `${{` in `{}`", s))?;
+            let reference = &after[..end];
+            let (name, default) = match reference.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (reference, None),
+            };
+            match (std::env::var(name), default) {
+                (Ok(value), Some(default)) if value.is_empty() => expanded.push_str(default),
+                (Ok(value), _) => expanded.push_str(&value),
+                (Err(_), Some(default)) => expanded.push_str(default),
+                (Err(_), None) => {
+                    return Err(anyhow!("environment variable `{}` is not defined", name))
+                }
+            }
+            rest = &after[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
 fn parse_string_array(array: Vec<Value>, prop_name: &str) -> Result<Vec<String>> {
     let mut parsed = Vec::new();
     for value in array {
@@ -122,6 +1153,111 @@ fn parse_string_array(array: Vec<Value>, prop_name: &str) -> Result<Vec<String>>
     Ok(parsed)
 }
 
+fn parse_additional_image(value: Value) -> Result<AdditionalImage> {
+    let table = match value {
+        Value::Table(table) => table,
+        other => {
+            return Err(anyhow!(
+                "additional-images must be a list of tables, found `{}`",
+                other
+            ))
+        }
+    };
+    let mut image = AdditionalImage {
+        suffix: String::new(),
+        min_size: 0,
+        block_size: 512,
+    };
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("suffix", Value::String(suffix)) => image.suffix = suffix,
+            ("min-size", value) => image.min_size = parse_size(&value, "min-size")?,
+            ("block-size", value) => image.block_size = parse_size(&value, "block-size")?,
+            (key, value) => {
+                return Err(anyhow!(
+                    "unexpected `additional-images` key `{}` with value `{}`",
+                    key,
+                    value
+                ))
+            }
+        }
+    }
+    if image.suffix.is_empty() {
+        return Err(anyhow!(
+            "additional-images entries need a non-empty `suffix`"
+        ));
+    }
+    if image.block_size == 0 {
+        return Err(anyhow!("additional-images `block-size` must not be zero"));
+    }
+    Ok(image)
+}
+
+fn parse_port_forward(value: Value) -> Result<PortForward> {
+    let table = match value {
+        Value::Table(table) => table,
+        other => {
+            return Err(anyhow!(
+                "port-forward must be a list of tables, found `{}`",
+                other
+            ))
+        }
+    };
+    let port = |value: Value, name: &str| match value {
+        Value::Integer(port) if (1..=u16::MAX.into()).contains(&port) => Ok(port as u16),
+        _ => Err(anyhow!("port-forward `{}` must be a port number", name)),
+    };
+    let mut host = None;
+    let mut guest = None;
+    let mut protocol = "tcp".to_owned();
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("host", value) => host = Some(port(value, "host")?),
+            ("guest", value) => guest = Some(port(value, "guest")?),
+            ("protocol", Value::String(p)) if p == "tcp" || p == "udp" => protocol = p,
+            ("protocol", _) => {
+                return Err(anyhow!("port-forward `protocol` must be `tcp` or `udp`"))
+            }
+            (key, value) => {
+                return Err(anyhow!(
+                    "unexpected `port-forward` key `{}` with value `{}`",
+                    key,
+                    value
+                ))
+            }
+        }
+    }
+    Ok(PortForward {
+        host: host.ok_or_else(|| anyhow!("port-forward entries need a `host` port"))?,
+        guest: guest.ok_or_else(|| anyhow!("port-forward entries need a `guest` port"))?,
+        protocol,
+    })
+}
+
+/// Parses a size in bytes given as integer or as string with a `K`, `M`, or `G` suffix.
+fn parse_size(value: &Value, prop_name: &str) -> Result<u64> {
+    let err = || {
+        anyhow!(
+            "{} must be a size such as `512`, `64K`, `64M`, or `1G`",
+            prop_name
+        )
+    };
+    match value {
+        Value::Integer(size) if !size.is_negative() => Ok(*size as u64),
+        Value::String(size) => {
+            let (number, factor) = match size.chars().last() {
+                Some('K') => (&size[..size.len() - 1], 1 << 10),
+                Some('M') => (&size[..size.len() - 1], 1 << 20),
+                Some('G') => (&size[..size.len() - 1], 1 << 30),
+                _ => (size.as_str(), 1),
+            };
+            let number: u64 = number.parse().map_err(|_| err())?;
+            number.checked_mul(factor).ok_or_else(err)
+        }
+        _ => Err(err()),
+    }
+}
+
 #[derive(Default)]
 struct ConfigBuilder {
     build_command: Option<Vec<String>>,
@@ -129,26 +1265,245 @@ struct ConfigBuilder {
     run_args: Option<Vec<String>>,
     test_args: Option<Vec<String>>,
     test_timeout: Option<u32>,
+    test_timeouts: Option<BTreeMap<String, u32>>,
     test_success_exit_code: Option<i32>,
+    doctest_success_exit_code: Option<i32>,
+    run_success_exit_code: Option<i32>,
+    test_exit_code_remap: Option<BTreeMap<i32, i32>>,
+    test_exit_device: Option<String>,
     test_no_reboot: Option<bool>,
+    test_serial_index: Option<u32>,
+    cache: Option<bool>,
+    incremental: Option<bool>,
+    ovmf_path: Option<PathBuf>,
+    initrd: Option<PathBuf>,
+    qemu_debug_flags: Option<String>,
+    qemu_debug_log: Option<PathBuf>,
+    run_working_dir: Option<PathBuf>,
+    legacy_image: Option<bool>,
+    shared_target_dir: Option<bool>,
+    strip_symbols: Option<bool>,
+    check_boot_signature: Option<bool>,
+    bootloader_manifest_path: Option<PathBuf>,
+    bootloader_cargo: Option<PathBuf>,
+    bootloader_locked: Option<bool>,
+    bootloader_binary_feature: Option<bool>,
+    auto_build_std: Option<bool>,
+    kernel_env_var: Option<String>,
+    kernel_manifest_env_var: Option<String>,
+    additional_images: Option<Vec<AdditionalImage>>,
+    post_image_command: Option<Vec<String>>,
+    pre_build_command: Option<Vec<String>>,
+    kernel_linker_script: Option<PathBuf>,
+    enable_rng: Option<bool>,
+    dedup_qemu_args: Option<bool>,
+    kill_grace_period: Option<u32>,
+    qemu_start_delay: Option<u64>,
+    target_json_dir: Option<PathBuf>,
+    metadata_retries: Option<u32>,
+    single_pass_build: Option<bool>,
+    image_start_offset: Option<u64>,
+    partition_table: Option<PartitionTable>,
+    image_type: Option<ImageType>,
+    boot_mode: Option<BootMode>,
+    arch: Option<Arch>,
+    port_forwards: Option<Vec<PortForward>>,
+    qemu_nic: Option<String>,
+    accel: Option<Accel>,
+    partition_start_lba: Option<u32>,
+    kernel_target_features: Option<Vec<String>>,
 }
 
-impl Into<Config> for ConfigBuilder {
-    fn into(self) -> Config {
+impl Default for Config {
+    /// Returns the configuration that is used if no keys are set.
+    fn default() -> Self {
+        ConfigBuilder::default().into()
+    }
+}
+
+impl From<ConfigBuilder> for Config {
+    fn from(builder: ConfigBuilder) -> Self {
         Config {
-            build_command: self.build_command.unwrap_or_else(|| vec!["build".into()]),
-            run_command: self.run_command.unwrap_or_else(|| {
-                vec![
-                    "qemu-system-x86_64".into(),
-                    "-drive".into(),
-                    "format=raw,file={}".into(),
-                ]
-            }),
-            run_args: self.run_args,
-            test_args: self.test_args,
-            test_timeout: self.test_timeout.unwrap_or(60 * 5),
-            test_success_exit_code: self.test_success_exit_code,
-            test_no_reboot: self.test_no_reboot.unwrap_or(true),
+            build_command: builder
+                .build_command
+                .unwrap_or_else(|| vec!["build".into()]),
+            run_command_configured: builder.run_command.is_some(),
+            run_command: builder
+                .run_command
+                .unwrap_or_else(|| Arch::X86_64.default_run_command()),
+            run_args: builder.run_args,
+            test_args: builder.test_args,
+            test_timeout: builder.test_timeout.unwrap_or(60 * 5),
+            test_timeouts: builder.test_timeouts.unwrap_or_default(),
+            test_success_exit_code: builder.test_success_exit_code,
+            doctest_success_exit_code: builder.doctest_success_exit_code,
+            run_success_exit_code: builder.run_success_exit_code,
+            test_exit_code_remap: builder.test_exit_code_remap.unwrap_or_default(),
+            test_exit_device: builder.test_exit_device,
+            test_no_reboot: builder.test_no_reboot.unwrap_or(true),
+            test_serial_index: builder.test_serial_index.unwrap_or(0),
+            cache: builder.cache.unwrap_or(false),
+            incremental: builder.incremental.unwrap_or(false),
+            ovmf_path: builder.ovmf_path,
+            initrd: builder.initrd,
+            qemu_debug_flags: builder.qemu_debug_flags,
+            qemu_debug_log: builder.qemu_debug_log,
+            run_working_dir: builder.run_working_dir,
+            legacy_image: builder.legacy_image.unwrap_or(false),
+            shared_target_dir: builder.shared_target_dir.unwrap_or(false),
+            strip_symbols: builder.strip_symbols.unwrap_or(false),
+            check_boot_signature: builder.check_boot_signature.unwrap_or(true),
+            bootloader_manifest_path: builder.bootloader_manifest_path,
+            bootloader_cargo: builder.bootloader_cargo,
+            bootloader_locked: builder.bootloader_locked.unwrap_or(false),
+            bootloader_binary_feature: builder.bootloader_binary_feature.unwrap_or(true),
+            auto_build_std: builder.auto_build_std.unwrap_or(true),
+            kernel_env_var: builder.kernel_env_var.unwrap_or_else(|| "KERNEL".into()),
+            kernel_manifest_env_var: builder
+                .kernel_manifest_env_var
+                .unwrap_or_else(|| "KERNEL_MANIFEST".into()),
+            additional_images: builder.additional_images.unwrap_or_default(),
+            post_image_command: builder.post_image_command,
+            pre_build_command: builder.pre_build_command,
+            kernel_linker_script: builder.kernel_linker_script,
+            enable_rng: builder.enable_rng.unwrap_or(false),
+            dedup_qemu_args: builder.dedup_qemu_args.unwrap_or(false),
+            kill_grace_period: builder.kill_grace_period.unwrap_or(2),
+            qemu_start_delay: builder.qemu_start_delay.unwrap_or(0),
+            target_json_dir: builder.target_json_dir,
+            metadata_retries: builder.metadata_retries.unwrap_or(2),
+            single_pass_build: builder.single_pass_build.unwrap_or(false),
+            image_start_offset: builder.image_start_offset.unwrap_or(0),
+            partition_table: builder.partition_table.unwrap_or(PartitionTable::None),
+            image_type: builder.image_type.unwrap_or(ImageType::Raw),
+            boot_mode: builder.boot_mode.unwrap_or(BootMode::DiskImage),
+            arch: builder.arch,
+            port_forwards: builder.port_forwards.unwrap_or_default(),
+            qemu_nic: builder.qemu_nic.unwrap_or_else(|| "e1000".into()),
+            accel: builder.accel,
+            partition_start_lba: builder.partition_start_lba.unwrap_or(2048),
+            kernel_target_features: builder.kernel_target_features.unwrap_or_default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(toml: &str) -> toml::value::Table {
+        match toml.parse::<Value>().unwrap() {
+            Value::Table(table) => table,
+            other => panic!("not a table: {}", other),
+        }
+    }
+
+    const PROFILE_METADATA: &str = r#"
+        test-timeout = 300
+        run-args = ["-display", "none"]
+
+        [profile.dev]
+        test-timeout = 600
+
+        [profile.release]
+        test-timeout = 60
+        run-args = []
+    "#;
+
+    #[test]
+    fn merge_dev_profile() {
+        let merged = merge_profile(&table(PROFILE_METADATA), Some("dev")).unwrap();
+        assert_eq!(
+            merged,
+            table(
+                r#"
+                test-timeout = 600
+                run-args = ["-display", "none"]
+                "#
+            )
+        );
+    }
+
+    #[test]
+    fn merge_release_profile() {
+        let merged = merge_profile(&table(PROFILE_METADATA), Some("release")).unwrap();
+        assert_eq!(
+            merged,
+            table(
+                r#"
+                test-timeout = 60
+                run-args = []
+                "#
+            )
+        );
+    }
+
+    #[test]
+    fn merge_without_profile() {
+        let expected = table(
+            r#"
+            test-timeout = 300
+            run-args = ["-display", "none"]
+            "#,
+        );
+        let metadata = table(PROFILE_METADATA);
+        assert_eq!(merge_profile(&metadata, None).unwrap(), expected);
+        assert_eq!(merge_profile(&metadata, Some("bench")).unwrap(), expected);
+    }
+
+    #[test]
+    fn merge_invalid_profile() {
+        assert!(merge_profile(&table("profile = 1"), Some("dev")).is_err());
+        assert!(merge_profile(&table("profile.dev = 1"), Some("dev")).is_err());
+    }
+
+    #[test]
+    fn expand_env_defined() {
+        env::set_var("BOOTIMAGE_TEST_DEFINED", "value");
+        assert_eq!(
+            expand_env("a-${BOOTIMAGE_TEST_DEFINED}-b").unwrap(),
+            "a-value-b"
+        );
+        assert_eq!(
+            expand_env("${BOOTIMAGE_TEST_DEFINED:-default}").unwrap(),
+            "value"
+        );
+    }
+
+    #[test]
+    fn expand_env_undefined() {
+        env::remove_var("BOOTIMAGE_TEST_UNDEFINED");
+        let err = expand_env("${BOOTIMAGE_TEST_UNDEFINED}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "environment variable `BOOTIMAGE_TEST_UNDEFINED` is not defined"
+        );
+    }
+
+    #[test]
+    fn expand_env_default() {
+        env::remove_var("BOOTIMAGE_TEST_DEFAULT");
+        assert_eq!(
+            expand_env("${BOOTIMAGE_TEST_DEFAULT:-fallback}").unwrap(),
+            "fallback"
+        );
+        env::set_var("BOOTIMAGE_TEST_DEFAULT_EMPTY", "");
+        assert_eq!(
+            expand_env("${BOOTIMAGE_TEST_DEFAULT_EMPTY:-fallback}").unwrap(),
+            "fallback"
+        );
+        assert_eq!(expand_env("${BOOTIMAGE_TEST_DEFAULT:-}").unwrap(), "");
+    }
+
+    #[test]
+    fn expand_env_dollar() {
+        assert_eq!(expand_env("$${HOME}").unwrap(), "${HOME}");
+        assert_eq!(expand_env("a$b$").unwrap(), "a$b$");
+    }
+
+    #[test]
+    fn expand_env_unterminated() {
+        let err = expand_env("a${HOME").unwrap_err();
+        assert_eq!(err.to_string(), "unterminated `${` in `a${HOME`");
+    }
+}
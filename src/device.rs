@@ -0,0 +1,209 @@
+//! Provides a function for writing a disk image to a block device (e.g. a USB stick).
+
+use crate::color::{self, Color, Stream};
+use std::{
+    fs, io,
+    io::{Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// Writes the given disk image to the given device and flushes it.
+///
+/// Prints the size of the device and a warning before writing. Since this overwrites all data
+/// at the start of the device, nothing is written unless `force` is set.
+///
+/// If the device is a FIFO (named pipe), the already padded disk image is streamed into it
+/// instead. Since this doesn't overwrite any data, `force` is not required in this case.
+pub fn write_image(image_path: &Path, device_path: &Path, force: bool) -> Result<(), WriteError> {
+    let io_error = |message| move |error| WriteError::Io { message, error };
+
+    let mut image = fs::File::open(image_path).map_err(io_error("failed to open disk image"))?;
+    if is_fifo(device_path) {
+        // opening blocks until the reading side of the FIFO is opened
+        let mut fifo = fs::OpenOptions::new()
+            .write(true)
+            .open(device_path)
+            .map_err(io_error("failed to open FIFO"))?;
+        io::copy(&mut image, &mut fifo).map_err(io_error("failed to write disk image to FIFO"))?;
+        return Ok(());
+    }
+    let image_size = image
+        .metadata()
+        .map_err(io_error("failed to read disk image metadata"))?
+        .len();
+    let mut device = fs::OpenOptions::new()
+        .write(true)
+        .open(device_path)
+        .map_err(io_error("failed to open device"))?;
+    let device_size = device
+        .seek(SeekFrom::End(0))
+        .map_err(io_error("failed to determine device size"))?;
+    // regular files report their current length, which can grow
+    if is_block_device(&device)? && image_size > device_size {
+        return Err(WriteError::DeviceTooSmall {
+            device: device_path.to_owned(),
+            device_size,
+            image_size,
+        });
+    }
+
+    eprintln!(
+        "{}: writing `{}` ({} bytes) to `{}` ({} bytes) overwrites the data on the device",
+        color::paint("WARNING", Color::Yellow, Stream::Stderr),
+        image_path.display(),
+        image_size,
+        device_path.display(),
+        device_size,
+    );
+    for mount in mounts_of(device_path) {
+        eprintln!(
+            "{}: the device is mounted at `{}`",
+            color::paint("WARNING", Color::Yellow, Stream::Stderr),
+            mount
+        );
+    }
+    if !force {
+        return Err(WriteError::ForceRequired {
+            device: device_path.to_owned(),
+        });
+    }
+
+    device
+        .seek(SeekFrom::Start(0))
+        .map_err(io_error("failed to seek to the start of the device"))?;
+    io::copy(&mut image, &mut device).map_err(io_error("failed to write disk image to device"))?;
+    device
+        .sync_all()
+        .map_err(io_error("failed to flush device"))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_block_device(file: &fs::File) -> Result<bool, WriteError> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = file.metadata().map_err(|error| WriteError::Io {
+        message: "failed to read device metadata",
+        error,
+    })?;
+    Ok(metadata.file_type().is_block_device())
+}
+
+#[cfg(not(unix))]
+fn is_block_device(_file: &fs::File) -> Result<bool, WriteError> {
+    Ok(false)
+}
+
+/// Returns whether the given path is a FIFO (named pipe), which can't be seeked or synced.
+#[cfg(unix)]
+fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_path: &Path) -> bool {
+    false
+}
+
+/// Returns the mount points of the given device and its partitions.
+///
+/// Only supported on Linux (through `/proc/mounts`). Returns an empty list on other
+/// platforms.
+fn mounts_of(device_path: &Path) -> Vec<String> {
+    let device = device_path
+        .canonicalize()
+        .unwrap_or_else(|_| device_path.to_owned());
+    let device = device.to_string_lossy();
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let source = fields.next()?;
+            let target = fields.next()?;
+            if is_device_or_partition(source, &device) {
+                Some(target.to_owned())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Returns whether `source` is the given device or one of its partitions.
+///
+/// Partitions have a number suffix (e.g. `/dev/sda1`), which is prefixed with `p` if the
+/// device name ends with a digit (e.g. `/dev/nvme0n1p1`).
+fn is_device_or_partition(source: &str, device: &str) -> bool {
+    let suffix = match source.strip_prefix(device) {
+        Some("") => return true,
+        Some(suffix) => suffix,
+        None => return false,
+    };
+    let number = if device.ends_with(|c: char| c.is_ascii_digit()) {
+        match suffix.strip_prefix('p') {
+            Some(number) => number,
+            None => return false,
+        }
+    } else {
+        suffix.strip_prefix('p').unwrap_or(suffix)
+    };
+    !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Writing a disk image to a device failed.
+#[derive(Debug, Error)]
+pub enum WriteError {
+    /// Writing to the device was not confirmed through `--force`
+    #[error("Refusing to overwrite `{}` without `--force`", device.display())]
+    ForceRequired {
+        /// The device that should be written to
+        device: PathBuf,
+    },
+
+    /// The disk image does not fit on the device
+    #[error(
+        "The disk image ({image_size} bytes) does not fit on `{}` ({device_size} bytes)",
+        device.display()
+    )]
+    DeviceTooSmall {
+        /// The device that should be written to
+        device: PathBuf,
+        /// The size of the device in bytes
+        device_size: u64,
+        /// The size of the disk image in bytes
+        image_size: u64,
+    },
+
+    /// An unexpected I/O error occurred
+    #[error("I/O error: {message}:\n{error}")]
+    Io {
+        /// Description of the failed I/O operation
+        message: &'static str,
+        /// The I/O error that occurred
+        error: io::Error,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_or_partition() {
+        assert!(is_device_or_partition("/dev/sda", "/dev/sda"));
+        assert!(is_device_or_partition("/dev/sda1", "/dev/sda"));
+        assert!(is_device_or_partition("/dev/sda12", "/dev/sda"));
+        assert!(!is_device_or_partition("/dev/sdab", "/dev/sda"));
+        assert!(!is_device_or_partition("/dev/sdab1", "/dev/sda"));
+        assert!(!is_device_or_partition("/dev/sd", "/dev/sda"));
+
+        assert!(is_device_or_partition("/dev/nvme0n1p2", "/dev/nvme0n1"));
+        assert!(!is_device_or_partition("/dev/nvme0n10", "/dev/nvme0n1"));
+        assert!(!is_device_or_partition("/dev/nvme0n1p", "/dev/nvme0n1"));
+        assert!(!is_device_or_partition("/dev/loop10", "/dev/loop1"));
+    }
+}
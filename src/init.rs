@@ -0,0 +1,221 @@
+//! Implements the `cargo bootimage init` command, which scaffolds a minimal bootable kernel.
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+const MAIN_RS: &str = r#"#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use core::panic::PanicInfo;
+
+/// This function is called on panic.
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    // this function is the entry point, since the linker looks for a function
+    // named `_start` by default
+    loop {}
+}
+"#;
+
+const TARGET_JSON: &str = r#"{
+    "llvm-target": "x86_64-unknown-none",
+    "data-layout": "e-m:e-p270:32:32-p271:32:32-p272:64:64-i64:64-i128:128-f80:128-n8:16:32:64-S128",
+    "arch": "x86_64",
+    "target-endian": "little",
+    "target-pointer-width": 64,
+    "target-c-int-width": 32,
+    "os": "none",
+    "linker-flavor": "ld.lld",
+    "linker": "rust-lld",
+    "panic-strategy": "abort",
+    "disable-redzone": true,
+    "features": "-mmx,-sse,+soft-float",
+    "rustc-abi": "x86-softfloat"
+}
+"#;
+
+/// Creates a minimal bootable kernel project in the given directory.
+///
+/// The directory is created if it doesn't exist. The package is named after the directory.
+/// Returns an error without writing anything if any of the files already exists.
+///
+/// Returns the paths of the created files.
+pub fn init(dir: &Path) -> Result<Vec<PathBuf>> {
+    let name = package_name(dir)?;
+    let target_name = format!("x86_64-{}", name);
+
+    let cargo_toml = format!(
+        r#"[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2018"
+
+[dev-dependencies]
+bootloader = "0.9"
+
+[package.metadata.bootimage]
+# see `cargo bootimage --help` for the available options
+"#,
+        name = name
+    );
+    let cargo_config = format!(
+        r#"[unstable]
+build-std = ["core", "compiler_builtins"]
+build-std-features = ["compiler-builtins-mem"]
+
+[build]
+target = "{target_name}.json"
+
+[target.'cfg(target_os = "none")']
+runner = "bootimage runner"
+"#,
+        target_name = target_name
+    );
+
+    let files = [
+        (dir.join("Cargo.toml"), cargo_toml),
+        (dir.join("src").join("main.rs"), MAIN_RS.to_owned()),
+        (dir.join(".cargo").join("config.toml"), cargo_config),
+        (
+            dir.join(format!("{}.json", target_name)),
+            TARGET_JSON.to_owned(),
+        ),
+    ];
+
+    let refuse_overwrite = |existing: &[&PathBuf]| {
+        let existing: Vec<_> = existing
+            .iter()
+            .map(|path| format!("`{}`", path.display()))
+            .collect();
+        anyhow!(
+            "refusing to overwrite existing files: {}",
+            existing.join(", ")
+        )
+    };
+    let existing: Vec<_> = files
+        .iter()
+        .map(|(path, _)| path)
+        .filter(|path| path.exists())
+        .collect();
+    if !existing.is_empty() {
+        return Err(refuse_overwrite(&existing));
+    }
+
+    let mut created = Vec::new();
+    for (path, content) in &files {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create directory `{}`", parent.display()))?;
+        }
+        // `create_new` also refuses files that were created after the check above
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(refuse_overwrite(&[path]));
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to create `{}`", path.display()))
+            }
+        };
+        file.write_all(content.as_bytes())
+            .with_context(|| format!("failed to write `{}`", path.display()))?;
+        created.push(path.clone());
+    }
+    Ok(created)
+}
+
+/// Derives the package name from the name of the given directory.
+fn package_name(dir: &Path) -> Result<String> {
+    let absolute = if dir.exists() {
+        dir.canonicalize()
+            .with_context(|| format!("failed to canonicalize `{}`", dir.display()))?
+    } else {
+        dir.to_owned()
+    };
+    let name = absolute
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("failed to derive package name from `{}`", dir.display()))?;
+    let valid = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && !name.starts_with(|c: char| c.is_ascii_digit());
+    if !valid {
+        return Err(anyhow!(
+            "the directory name `{}` is not a valid package name",
+            name
+        ));
+    }
+    Ok(name.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn init_creates_project() {
+        let dir = std::env::temp_dir()
+            .join(format!("bootimage-init-{}", std::process::id()))
+            .join("my_kernel");
+
+        let created = init(&dir).unwrap();
+        assert_eq!(
+            created,
+            [
+                dir.join("Cargo.toml"),
+                dir.join("src").join("main.rs"),
+                dir.join(".cargo").join("config.toml"),
+                dir.join("x86_64-my_kernel.json"),
+            ]
+        );
+        let cargo_toml = fs::read_to_string(dir.join("Cargo.toml")).unwrap();
+        assert!(cargo_toml.contains("name = \"my_kernel\""));
+        let cargo_config = fs::read_to_string(dir.join(".cargo").join("config.toml")).unwrap();
+        assert!(cargo_config.contains("target = \"x86_64-my_kernel.json\""));
+        assert_eq!(
+            fs::read_to_string(dir.join("src").join("main.rs")).unwrap(),
+            MAIN_RS
+        );
+        let target = json::parse(&fs::read_to_string(&created[3]).unwrap()).unwrap();
+        assert_eq!(target["target-pointer-width"], 64);
+        assert_eq!(target["target-c-int-width"], 32);
+        assert_eq!(target["rustc-abi"], "x86-softfloat");
+        assert!(target["executables"].is_null());
+
+        // refuses to overwrite and leaves the existing files unchanged
+        fs::write(dir.join("src").join("main.rs"), "changed").unwrap();
+        let err = init(&dir).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("refusing to overwrite existing files: "),
+            "{}",
+            err
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("src").join("main.rs")).unwrap(),
+            "changed"
+        );
+
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn invalid_package_name() {
+        assert!(package_name(Path::new("/tmp/1kernel")).is_err());
+        assert!(package_name(Path::new("/tmp/my kernel")).is_err());
+        assert_eq!(
+            package_name(Path::new("/tmp/my-kernel")).unwrap(),
+            "my-kernel"
+        );
+    }
+}
@@ -1,13 +1,29 @@
 //! Provides functions to create a bootable OS image from a kernel binary.
 //!
 //! This crate is mainly built as a binary tool. Run `cargo install bootimage` to install it.
+//!
+//! With `default-features = false`, only the `no_std` compatible [`qemu_exit`] module is
+//! available, which allows kernels to use it as a dependency.
 
+#![cfg_attr(not(feature = "std"), no_std)]
 #![warn(missing_docs)]
 
+#[cfg(feature = "std")]
 pub mod args;
+#[cfg(feature = "std")]
 pub mod builder;
+#[cfg(feature = "std")]
+pub mod color;
+#[cfg(feature = "std")]
 pub mod config;
+#[cfg(feature = "std")]
+pub mod device;
+#[cfg(feature = "std")]
+pub mod init;
+pub mod qemu_exit;
+#[cfg(feature = "std")]
 pub mod run;
 
 /// Contains help messages for the command line application.
+#[cfg(feature = "std")]
 pub mod help;
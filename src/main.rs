@@ -1,12 +1,20 @@
 /// Executable for `bootimage runner`.
 use anyhow::{anyhow, Context, Result};
 use bootimage::{
-    args::{RunnerArgs, RunnerCommand},
+    args::{Firmware, RunnerArgs, RunnerCommand},
     builder::Builder,
-    config, help, run,
+    color::{self, Color, Stream},
+    config::{self, BootMode},
+    help, run,
 };
 use std::process;
-use std::{env, path::Path};
+use std::{
+    collections::hash_map::DefaultHasher,
+    env, fs,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+};
 
 pub fn main() -> Result<()> {
     let mut raw_args = env::args();
@@ -23,7 +31,18 @@ pub fn main() -> Result<()> {
             file_stem
         ));
     }
-    match raw_args.next().as_deref() {
+
+    // parse global arguments that precede the subcommand
+    let mut subcommand = raw_args.next();
+    while let Some(arg) = subcommand.as_deref() {
+        match color::parse_color_arg(arg, &mut raw_args)? {
+            Some(choice) => color::set_color_choice(choice),
+            None => break,
+        }
+        subcommand = raw_args.next();
+    }
+
+    match subcommand.as_deref() {
         Some("runner") => {},
         Some("--help") | Some("-h") => {
             help::print_help();
@@ -62,18 +81,21 @@ pub fn main() -> Result<()> {
 
 pub(crate) fn runner(args: RunnerArgs) -> Result<i32> {
     let mut builder = Builder::new(None)?;
-    let config = config::read_config(builder.manifest_path())?;
+    builder.set_debug_artifacts(args.debug_artifacts);
+    let mut config = match args.profile() {
+        Some(profile) => config::read_config_for_profile(builder.manifest_path(), &profile)?,
+        None => config::read_config(builder.manifest_path())?,
+    };
+    builder.set_metadata_retries(config.metadata_retries);
+    if args.arch.is_some() {
+        config.arch = args.arch;
+    }
     let exe_parent = args
         .executable
         .parent()
         .ok_or_else(|| anyhow!("kernel executable has no parent"))?;
-    let is_doctest = exe_parent
-        .file_name()
-        .ok_or_else(|| anyhow!("kernel executable's parent has no file name"))?
-        .to_str()
-        .ok_or_else(|| anyhow!("kernel executable's parent file name is not valid UTF-8"))?
-        .starts_with("rustdoctest");
-    let is_test = is_doctest || exe_parent.ends_with("deps");
+    let kind = run::classify_executable(&args.executable);
+    let is_test = kind.is_test();
 
     let bin_name = args
         .executable
@@ -98,14 +120,175 @@ pub(crate) fn runner(args: RunnerArgs) -> Result<i32> {
         .context("Failed to read CARGO_MANIFEST_DIR environment variable")?;
     let kernel_manifest_path = Path::new(&manifest_dir).join("Cargo.toml");
 
-    builder.create_bootimage(
-        &kernel_manifest_path,
-        &executable_canonicalized,
-        &output_bin_path,
-        args.quiet,
-    )?;
+    // with `boot-mode = "direct-kernel"`, QEMU boots the kernel executable directly
+    let direct_kernel = config.boot_mode == BootMode::DirectKernel;
+
+    // skip unchanged executables (e.g. test binaries during `cargo test`) if enabled
+    let hash_path = output_bin_path.with_extension("hash");
+    let hash = if config.incremental && !direct_kernel {
+        Some(
+            incremental_hash(&executable_canonicalized, &config)
+                .context("failed to hash kernel executable")?,
+        )
+    } else {
+        None
+    };
+    let unchanged = match &hash {
+        Some(hash) => {
+            output_bin_path.exists()
+                && fs::read_to_string(&hash_path).is_ok_and(|stored| stored == *hash)
+        }
+        None => false,
+    };
+    if unchanged {
+        if !args.quiet {
+            println!(
+                "Skipping disk image creation for unchanged `{}`",
+                args.executable.display()
+            );
+        }
+    } else if !direct_kernel {
+        builder.create_bootimage_with_config(
+            &kernel_manifest_path,
+            &executable_canonicalized,
+            &output_bin_path,
+            &config,
+            args.quiet,
+        )?;
+        if let Some(hash) = &hash {
+            fs::write(&hash_path, hash)
+                .with_context(|| format!("failed to write `{}`", hash_path.display()))?;
+        }
+    }
+
+    // bootimage itself only creates BIOS disk images, so the UEFI variant needs to be
+    // created separately (e.g. by the bootloader build)
+    let image_path = if direct_kernel {
+        executable_canonicalized.clone()
+    } else if args.firmware == Some(Firmware::Uefi) {
+        let uefi_image_path = exe_parent.join(format!("bootimage-uefi-{}.img", bin_name));
+        if !uefi_image_path.exists() {
+            return Err(anyhow!(
+                "`--uefi` was requested, but no UEFI image variant exists at `{}`.\n\n\
+                 This version of bootimage only builds BIOS disk images, so the UEFI image \
+                 needs to be created separately (e.g. by the bootloader build).",
+                uefi_image_path.display()
+            ));
+        }
+        uefi_image_path
+    } else {
+        output_bin_path
+    };
+
+    // Results of test executables are recorded for re-running only the failed tests. Doctests
+    // are compiled to temporary directories, so they are not considered.
+    let results_path = match kind {
+        run::ExecutableKind::Test => test_results_path(&executable_canonicalized),
+        _ => None,
+    };
+    let test_name = run::test_name(&args.executable);
+    // test names are only unique within a package
+    let package_key = kernel_manifest_path.display().to_string();
+    if env::var_os("BOOTIMAGE_RUNNER_FAILED").is_some_and(|v| v == "1") {
+        if let Some(path) = &results_path {
+            match read_test_results(path) {
+                Some(results) if results[package_key.as_str()][test_name.as_str()] == "ok" => {
+                    if !args.quiet {
+                        println!("Skipping test `{}` (passed in the previous run)", test_name);
+                    }
+                    return Ok(0);
+                }
+                Some(_) => {}
+                None => eprintln!(
+                    "{}: no results of a previous test run found at `{}`, running `{}`",
+                    color::paint("WARNING", Color::Yellow, Stream::Stderr),
+                    path.display(),
+                    test_name
+                ),
+            }
+        }
+    }
+
+    // Allows using bootimage as a cargo runner for only creating the disk image
+    if env::var_os("BOOTIMAGE_RUNNER_NO_RUN").is_some_and(|v| v == "1") {
+        if !args.quiet && !direct_kernel {
+            println!("Created disk image at `{}`", image_path.display());
+        }
+        return Ok(0);
+    }
+
+    let result = run::run(config, args, &image_path, is_test);
+    if let Some(path) = &results_path {
+        let passed = matches!(result, Ok(0));
+        write_test_result(path, &package_key, &test_name, passed)
+            .with_context(|| format!("failed to write test results to `{}`", path.display()))?;
+    }
+
+    Ok(result?)
+}
 
-    let exit_code = run::run(config, args, &output_bin_path, is_test)?;
+/// Returns the path of the file that records the results of the last test run.
+///
+/// The file is placed in `bootimage/tester` in the target directory, which is found
+/// through the `CACHEDIR.TAG` file that cargo creates in it.
+fn test_results_path(executable: &Path) -> Option<PathBuf> {
+    let target_dir = executable
+        .ancestors()
+        .find(|dir| dir.join("CACHEDIR.TAG").exists())?;
+    Some(
+        target_dir
+            .join("bootimage")
+            .join("tester")
+            .join("last-results.json"),
+    )
+}
+
+/// Reads the recorded test results.
+///
+/// The results map the manifest paths of the packages to objects that map the test names to
+/// `"ok"` or `"failed"`.
+fn read_test_results(path: &Path) -> Option<json::JsonValue> {
+    let content = fs::read_to_string(path).ok()?;
+    json::parse(&content)
+        .ok()
+        .filter(json::JsonValue::is_object)
+}
+
+/// Records the result of the given test of the given package, keeping the results of other
+/// tests.
+fn write_test_result(path: &Path, package: &str, test_name: &str, passed: bool) -> io::Result<()> {
+    let mut results = read_test_results(path).unwrap_or_else(json::JsonValue::new_object);
+    results[package][test_name] = if passed { "ok" } else { "failed" }.into();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, results.pretty(2))
+}
+
+/// Hashes the content of the given executable together with the effective configuration.
+///
+/// Used by the `incremental` option to detect whether the disk image is up to date.
+fn incremental_hash(executable: &Path, config: &config::Config) -> io::Result<String> {
+    let mut hasher = DefaultHasher::new();
+    fs::read(executable)?.hash(&mut hasher);
+    config.to_toml().hash(&mut hasher);
+    Ok(format!("{:016x}", hasher.finish()))
+}
 
-    Ok(exit_code)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_results_are_keyed_by_package() {
+        let path = env::temp_dir()
+            .join(format!("bootimage-test-results-{}", process::id()))
+            .join("last-results.json");
+        write_test_result(&path, "/a/Cargo.toml", "basic_boot", true).unwrap();
+        write_test_result(&path, "/b/Cargo.toml", "basic_boot", false).unwrap();
+        let results = read_test_results(&path).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(results["/a/Cargo.toml"]["basic_boot"], "ok");
+        assert_eq!(results["/b/Cargo.toml"]["basic_boot"], "failed");
+    }
 }
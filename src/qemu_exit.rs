@@ -0,0 +1,49 @@
+//! Exit codes for kernels that exit QEMU through the `isa-debug-exit` device.
+//!
+//! This module only depends on `core`, so it can be used from `no_std` kernels by depending
+//! on bootimage with `default-features = false`.
+//!
+//! The `isa-debug-exit` device makes QEMU exit with the status `(value << 1) | 1` when
+//! `value` is written to its I/O port. For [`ExitCode::Success`], this results in the exit
+//! status 33, which should be configured as the `test-success-exit-code` in the
+//! `package.metadata.bootimage` table:
+//!
+//! ```toml
+//! [package.metadata.bootimage]
+//! test-args = ["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"]
+//! test-success-exit-code = 33 # (0x10 << 1) | 1
+//! ```
+
+/// The I/O port of the `isa-debug-exit` device used in the `test-args` above.
+pub const ISA_DEBUG_EXIT_IOBASE: u16 = 0xf4;
+
+/// The size of the `isa-debug-exit` I/O port in bytes (i.e. a `u32` should be written).
+pub const ISA_DEBUG_EXIT_IOSIZE: u16 = 0x04;
+
+/// The QEMU `-device` argument that adds an `isa-debug-exit` device at
+/// [`ISA_DEBUG_EXIT_IOBASE`].
+pub const ISA_DEBUG_EXIT_DEVICE: &str = "isa-debug-exit,iobase=0xf4,iosize=0x04";
+
+/// The values that a kernel writes to the `isa-debug-exit` port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ExitCode {
+    /// The tests succeeded
+    Success = 0x10,
+    /// The tests failed
+    Failed = 0x11,
+}
+
+impl ExitCode {
+    /// Returns the value that should be written to the `isa-debug-exit` port.
+    pub const fn code(self) -> u32 {
+        self as u32
+    }
+
+    /// Returns the exit status of QEMU after writing this exit code.
+    ///
+    /// For [`ExitCode::Success`], this is the value for the `test-success-exit-code` key.
+    pub const fn qemu_exit_status(self) -> i32 {
+        ((self as i32) << 1) | 1
+    }
+}
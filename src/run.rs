@@ -1,12 +1,169 @@
 //! Provides a function for running a disk image in QEMU.
 
-use crate::{args::RunnerArgs, config::Config};
-use std::{io, path::Path, process, time::Duration};
+use crate::{
+    args::{Firmware, RunnerArgs},
+    config::{Accel, Config},
+};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
 use thiserror::Error;
 use wait_timeout::ChildExt;
 
+/// The kind of executable that cargo passed to the runner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutableKind {
+    /// A normal binary (e.g. from `cargo run`)
+    Bin,
+    /// A test executable (e.g. from `cargo test`)
+    Test,
+    /// A doctest executable (from `cargo test --doc`)
+    Doctest,
+}
+
+impl ExecutableKind {
+    /// Returns whether the executable should be run as a test.
+    pub fn is_test(self) -> bool {
+        match self {
+            ExecutableKind::Bin => false,
+            ExecutableKind::Test | ExecutableKind::Doctest => true,
+        }
+    }
+}
+
+/// Classifies the given executable based on its path.
+///
+/// Rustdoc places doctests in (a subdirectory of) a temporary `rustdoctest*` directory.
+/// Cargo places test executables (including tests with `harness = false`) in a `deps`
+/// directory and appends a metadata hash to their name (e.g. `basic_boot-1a2b3c4d5e6f7a8b`).
+/// The hash is also checked so that tests that are moved out of the `deps` directory (e.g.
+/// by a wrapper script) are still recognized.
+pub fn classify_executable(path: &Path) -> ExecutableKind {
+    let in_rustdoctest_dir = path
+        .parent()
+        .into_iter()
+        .flat_map(Path::ancestors)
+        .filter_map(Path::file_name)
+        .any(|name| name.to_string_lossy().starts_with("rustdoctest"));
+    if in_rustdoctest_dir {
+        return ExecutableKind::Doctest;
+    }
+    let in_deps_dir = path
+        .parent()
+        .is_some_and(|parent| parent.file_name() == Some("deps".as_ref()));
+    let has_hash = path
+        .file_stem()
+        .is_some_and(|stem| strip_cargo_hash(&stem.to_string_lossy()).is_some());
+    if in_deps_dir || has_hash {
+        ExecutableKind::Test
+    } else {
+        ExecutableKind::Bin
+    }
+}
+
+/// Run the given already built disk image in QEMU with default runner arguments.
+///
+/// This is a convenience wrapper around [`run`] for tools that already have a
+/// disk image and a `Config` and only want to reuse the QEMU launch behavior.
+///
+/// The `executable` is the kernel executable that the disk image was created from. It is
+/// substituted for `{kernel_elf}` in the run command and determines the test name (for
+/// `test-timeouts`), the target architecture, and whether the executable is a doctest.
+pub fn run_image(
+    config: Config,
+    executable: &Path,
+    image_path: &Path,
+    is_test: bool,
+) -> Result<i32, RunError> {
+    let args = RunnerArgs::new(executable.to_owned());
+    run(config, args, image_path, is_test)
+}
+
+/// Runs the given disk image headless and returns the QEMU exit code and the serial output.
+///
+/// The `kernel_elf` path is substituted for `{kernel_elf}` in the run command. The QEMU
+/// architecture is derived from the `arch` config key or the given target triple (like for
+/// the runner). The run command of the given `Config` is launched with `-display none` and the first
+/// serial port routed to a temporary file, which is read and removed after QEMU exited. The
+/// `test-exit-device` is attached if one is configured. Other arguments (e.g. `run-args`)
+/// are not applied. If QEMU doesn't exit within the given timeout, it is terminated and
+/// [`RunError::TestTimedOut`] is returned.
+pub fn run_capturing(
+    config: &Config,
+    image_path: &Path,
+    kernel_elf: &Path,
+    target_triple: Option<&str>,
+    timeout: Duration,
+) -> Result<(i32, String), RunError> {
+    static CAPTURE_COUNT: AtomicUsize = AtomicUsize::new(0);
+    let serial_path = std::env::temp_dir().join(format!(
+        "bootimage-serial-{}-{}.log",
+        process::id(),
+        CAPTURE_COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let run_command: Vec<_> = config
+        .run_command_for_arch(config.arch_for_target(target_triple))
+        .iter()
+        .map(|arg| substitute_paths(arg, image_path, kernel_elf))
+        .collect();
+    let mut command = process::Command::new(&run_command[0]);
+    command.args(&run_command[1..]);
+    command.arg("-display").arg("none");
+    command
+        .arg("-serial")
+        .arg(format!("file:{}", serial_path.display()));
+    if let Some(device) = &config.test_exit_device {
+        command.arg("-device").arg(device);
+    }
+
+    let mut child = command.spawn().map_err(|error| RunError::Io {
+        context: IoErrorContext::QemuTestCommand {
+            command: format!("{:?}", command),
+        },
+        error,
+    })?;
+    let exit_status = child
+        .wait_timeout(timeout)
+        .map_err(context(IoErrorContext::WaitWithTimeout))?;
+    let exit_status = match exit_status {
+        Some(exit_status) => exit_status,
+        None => {
+            let grace_period = Duration::from_secs(config.kill_grace_period.into());
+            terminate(&mut child, grace_period)?;
+            let _ = fs::remove_file(&serial_path);
+            return Err(RunError::TestTimedOut);
+        }
+    };
+    // QEMU doesn't create the file if it fails before starting the guest
+    let serial_output = match fs::read(&serial_path) {
+        Ok(output) => output,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => {
+            return Err(RunError::Io {
+                context: IoErrorContext::ReadSerialLog { path: serial_path },
+                error: err,
+            })
+        }
+    };
+    let _ = fs::remove_file(&serial_path);
+    let exit_code = exit_status.code().ok_or(RunError::NoQemuExitCode)?;
+    Ok((
+        exit_code,
+        String::from_utf8_lossy(&serial_output).into_owned(),
+    ))
+}
+
 /// Run the given disk image in QEMU.
 ///
+/// This is the supported entry point for running an existing disk image. It does
+/// not build anything, so the image at `image_path` must already exist.
+///
 /// Automatically takes into account the runner arguments and the run/test
 /// commands defined in the given `Config`. Since test executables are treated
 /// differently (run with a timeout and match exit status), the caller needs to
@@ -17,12 +174,55 @@ pub fn run(
     image_path: &Path,
     is_test: bool,
 ) -> Result<i32, RunError> {
+    let test_timeout = args
+        .timeout
+        .unwrap_or_else(|| config.test_timeout_for(&test_name(&args.executable)));
+    let working_dir = args
+        .run_dir
+        .clone()
+        .or_else(|| config.run_working_dir.clone());
+    // the image path must stay valid when the run command is executed in another directory
+    let absolute_image_path;
+    let image_path = if working_dir.is_some() && image_path.is_relative() {
+        let current_dir = std::env::current_dir().map_err(context(IoErrorContext::CurrentDir))?;
+        absolute_image_path = current_dir.join(image_path);
+        &absolute_image_path
+    } else {
+        image_path
+    };
+
+    let serial_log = if is_test && args.print_serial_on_failure {
+        Some(SerialLog::new(image_path))
+    } else {
+        None
+    };
+
+    let arch = args
+        .arch
+        .unwrap_or_else(|| config.arch_for_target(args.target_triple.as_deref()));
+    // absolute, so that it stays valid in another working directory
+    let kernel_elf = args
+        .executable
+        .canonicalize()
+        .unwrap_or_else(|_| args.executable.clone());
     let mut run_command: Vec<_> = config
-        .run_command
+        .run_command_for_arch(arch)
         .iter()
-        .map(|arg| arg.replace("{}", &format!("{}", image_path.display())))
+        .map(|arg| substitute_paths(arg, image_path, &kernel_elf))
         .collect();
     if is_test {
+        if let Some(log) = &serial_log {
+            for _ in 0..config.test_serial_index {
+                run_command.push("-serial".to_owned());
+                run_command.push("null".to_owned());
+            }
+            run_command.push("-serial".to_owned());
+            run_command.push(format!("file:{}", log.path.display()));
+        }
+        if let Some(device) = config.test_exit_device {
+            run_command.push("-device".to_owned());
+            run_command.push(device);
+        }
         if config.test_no_reboot {
             run_command.push("-no-reboot".to_owned());
         }
@@ -32,31 +232,123 @@ pub fn run(
     } else if let Some(args) = config.run_args {
         run_command.extend(args);
     }
+    if args.no_shutdown {
+        run_command.push("-no-shutdown".to_owned());
+    }
+    if config.enable_rng {
+        run_command.push("-device".to_owned());
+        run_command.push("virtio-rng-pci".to_owned());
+    }
+    if let Some(accel) = config.accel {
+        for name in accel_names(accel) {
+            run_command.push("-accel".to_owned());
+            run_command.push(name.to_string());
+        }
+    }
+    if !config.port_forwards.is_empty() {
+        let mut netdev = "user,id=net0".to_owned();
+        for forward in &config.port_forwards {
+            netdev.push(',');
+            netdev.push_str(&forward.hostfwd());
+        }
+        run_command.push("-netdev".to_owned());
+        run_command.push(netdev);
+        run_command.push("-device".to_owned());
+        run_command.push(format!("{},netdev=net0", config.qemu_nic));
+    }
+    match &args.firmware {
+        Some(Firmware::Uefi) => {
+            let ovmf_path = config.ovmf_path.ok_or(RunError::OvmfPathNotConfigured)?;
+            run_command.push("-bios".to_owned());
+            run_command.push(ovmf_path.display().to_string());
+        }
+        Some(Firmware::Custom(path)) => {
+            run_command.push("-bios".to_owned());
+            run_command.push(path.display().to_string());
+        }
+        Some(Firmware::Bios) | None => {}
+    }
+    if let Some(initrd) = args.initrd.as_ref().or(config.initrd.as_ref()) {
+        // absolute, so that it stays valid in another working directory
+        let initrd = initrd
+            .canonicalize()
+            .map_err(|_| RunError::InitrdNotFound {
+                path: initrd.clone(),
+            })?;
+        run_command.push("-initrd".to_owned());
+        run_command.push(initrd.display().to_string());
+    }
+    if let Some(flags) = &config.qemu_debug_flags {
+        run_command.push("-d".to_owned());
+        run_command.push(flags.clone());
+    }
+    if let Some(log) = &config.qemu_debug_log {
+        if let Some(dir) = log.parent() {
+            fs::create_dir_all(dir).map_err(context(IoErrorContext::CreateDebugLogDir {
+                path: dir.to_owned(),
+            }))?;
+        }
+        run_command.push("-D".to_owned());
+        run_command.push(log.display().to_string());
+        if !args.quiet {
+            println!("Writing QEMU debug log to `{}`", log.display());
+        }
+    }
     if let Some(args) = args.runner_args {
         run_command.extend(args);
     }
+    if config.dedup_qemu_args {
+        run_command = dedup_qemu_args(run_command);
+    }
+
+    if let Some(path) = &args.emit_qemu_command_file {
+        write_command_file(path, &run_command)?;
+        if !args.quiet {
+            println!("Wrote run command to `{}`", path.display());
+        }
+    }
+    if args.dry_run {
+        if !args.quiet {
+            println!("Would run: `{}`", quote_command(&run_command));
+        }
+        return Ok(0);
+    }
+
+    let start_delay = args.qemu_start_delay.unwrap_or(config.qemu_start_delay);
+    if start_delay > 0 {
+        thread::sleep(Duration::from_millis(start_delay));
+    }
 
     if !args.quiet {
-        println!("Running: `{}`", run_command.join(" "));
+        println!("Running: `{}`", quote_command(&run_command));
     }
     let mut command = process::Command::new(&run_command[0]);
     command.args(&run_command[1..]);
+    if let Some(dir) = &working_dir {
+        command.current_dir(dir);
+    }
 
-    let exit_code = if is_test {
+    // With `-no-shutdown`, QEMU keeps running after a test exits or crashes, so the test
+    // is waited for like a normal run and has to be terminated manually.
+    let exit_code = if is_test && !args.no_shutdown {
         let mut child = command.spawn().map_err(|error| RunError::Io {
             context: IoErrorContext::QemuTestCommand {
                 command: format!("{:?}", command),
             },
             error,
         })?;
-        let timeout = Duration::from_secs(config.test_timeout.into());
-        match child
+        let timeout = Duration::from_secs(test_timeout.into());
+        let grace_period = Duration::from_secs(config.kill_grace_period.into());
+        let exit_code = match child
             .wait_timeout(timeout)
             .map_err(context(IoErrorContext::WaitWithTimeout))?
         {
             None => {
-                child.kill().map_err(context(IoErrorContext::KillQemu))?;
-                child.wait().map_err(context(IoErrorContext::WaitForQemu))?;
+                terminate(&mut child, grace_period)?;
+                if let Some(log) = &serial_log {
+                    log.print();
+                    log.keep();
+                }
                 return Err(RunError::TestTimedOut);
             }
             Some(exit_status) => {
@@ -70,26 +362,353 @@ pub fn run(
                     }
                 }
                 let qemu_exit_code = exit_status.code().ok_or(RunError::NoQemuExitCode)?;
-                match config.test_success_exit_code {
+                let success_exit_code =
+                    if classify_executable(&args.executable) == ExecutableKind::Doctest {
+                        config
+                            .doctest_success_exit_code
+                            .or(config.test_success_exit_code)
+                    } else {
+                        config.test_success_exit_code
+                    };
+                match success_exit_code {
                     Some(code) if qemu_exit_code == code => 0,
+                    _ if config.test_exit_code_remap.contains_key(&qemu_exit_code) => {
+                        config.test_exit_code_remap[&qemu_exit_code]
+                    }
                     Some(_) if qemu_exit_code == 0 => 1,
                     _ => qemu_exit_code,
                 }
             }
+        };
+        if exit_code != 0 {
+            if let Some(log) = &serial_log {
+                log.print();
+            }
         }
+        exit_code
     } else {
-        let status = command.status().map_err(|error| RunError::Io {
+        let mut child = command.spawn().map_err(|error| RunError::Io {
             context: IoErrorContext::QemuRunCommand {
                 command: format!("{:?}", command),
             },
             error,
         })?;
-        status.code().unwrap_or(1)
+        // Forward SIGINT/SIGTERM to QEMU so that it can shut down cleanly and
+        // restore the terminal before we exit. On other platforms, the console
+        // already delivers Ctrl+C to the QEMU child process.
+        #[cfg(unix)]
+        let _signal_guard = signal::forward_to(child.id());
+        let status = child.wait().map_err(context(IoErrorContext::WaitForQemu))?;
+        let success_code = config.run_success_exit_code.filter(|_| !is_test);
+        match (status.code(), success_code) {
+            (Some(code), Some(success_code)) if code == success_code => 0,
+            (Some(0), Some(_)) => 1,
+            (Some(code), _) => code,
+            (None, _) => 1,
+        }
     };
 
+    if let Some(log) = &serial_log {
+        log.keep();
+    }
+
     Ok(exit_code)
 }
 
+/// Returns the names of the QEMU accelerators for the given `accel` setting.
+///
+/// For [`Accel::Auto`], the accelerator of the host platform is followed by `tcg`, which
+/// QEMU falls back to if the first one is not available (e.g. without access to `/dev/kvm`).
+fn accel_names(accel: Accel) -> &'static [&'static str] {
+    match accel {
+        Accel::Tcg => &["tcg"],
+        Accel::Kvm => &["kvm"],
+        Accel::Hvf => &["hvf"],
+        Accel::Whpx => &["whpx"],
+        Accel::Auto if cfg!(target_os = "linux") => &["kvm", "tcg"],
+        Accel::Auto if cfg!(target_os = "macos") => &["hvf", "tcg"],
+        Accel::Auto if cfg!(windows) => &["whpx", "tcg"],
+        Accel::Auto => &["tcg"],
+    }
+}
+
+/// Removes all but the last occurrence of single-valued QEMU options (e.g. `-m`).
+///
+/// This allows runner arguments to override the `run-args`/`test-args` of the config. A note
+/// is printed to stderr for each removed option. `-machine` is not deduplicated because QEMU
+/// merges the properties of multiple `-machine` options (e.g. `-machine q35 -machine
+/// accel=kvm`).
+fn dedup_qemu_args(command: Vec<String>) -> Vec<String> {
+    const SINGLE_VALUED: &[&str] = &["-display", "-m", "-cpu"];
+
+    let is_option_at = |index: usize| {
+        index > 0 && index + 1 < command.len() && SINGLE_VALUED.contains(&command[index].as_str())
+    };
+    let mut deduped = Vec::with_capacity(command.len());
+    let mut index = 0;
+    while index < command.len() {
+        if is_option_at(index) {
+            let option = &command[index];
+            let overridden = (index + 2..command.len())
+                .any(|later| command[later] == *option && is_option_at(later));
+            if overridden {
+                eprintln!(
+                    "Note: `{} {}` is overridden by a later `{}` argument",
+                    option,
+                    command[index + 1],
+                    option
+                );
+            } else {
+                deduped.push(command[index].clone());
+                deduped.push(command[index + 1].clone());
+            }
+            index += 2;
+        } else {
+            deduped.push(command[index].clone());
+            index += 1;
+        }
+    }
+    deduped
+}
+
+/// Terminates the given QEMU process without blocking indefinitely.
+///
+/// On Unix, QEMU is first asked to exit through `SIGTERM`. If it is still running after the
+/// grace period, it is killed through `SIGKILL`. If the process is not reaped within another
+/// grace period, a warning is printed and it is left behind.
+fn terminate(child: &mut process::Child, grace_period: Duration) -> Result<(), RunError> {
+    #[cfg(unix)]
+    {
+        signal::terminate(child.id());
+        let exited = child
+            .wait_timeout(grace_period)
+            .map_err(context(IoErrorContext::WaitForQemu))?;
+        if exited.is_some() {
+            return Ok(());
+        }
+    }
+    child.kill().map_err(context(IoErrorContext::KillQemu))?;
+    let exited = child
+        .wait_timeout(grace_period)
+        .map_err(context(IoErrorContext::WaitForQemu))?;
+    if exited.is_none() {
+        eprintln!(
+            "QEMU process {} did not exit after it was killed, leaving it behind",
+            child.id()
+        );
+    }
+    Ok(())
+}
+
+/// The serial output file of a test that is written for `--print-serial-on-failure`.
+///
+/// Each run writes to a file with a unique suffix (e.g. `bootimage-x.serial.1234-0.log`), so
+/// that a quickly repeated run of the same test can't read stale or clobbered output. The
+/// file is then renamed to the stable name (e.g. `bootimage-x.serial.log`). If the run fails
+/// before that, the file is removed on drop.
+struct SerialLog {
+    path: PathBuf,
+    stable_path: PathBuf,
+}
+
+impl SerialLog {
+    fn new(image_path: &Path) -> Self {
+        static RUN_COUNT: AtomicUsize = AtomicUsize::new(0);
+        let suffix = format!(
+            "serial.{}-{}.log",
+            process::id(),
+            RUN_COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        SerialLog {
+            path: image_path.with_extension(suffix),
+            stable_path: image_path.with_extension("serial.log"),
+        }
+    }
+
+    /// Prints the serial output of this run to stderr.
+    fn print(&self) {
+        match fs::read_to_string(&self.path) {
+            Ok(output) => {
+                eprintln!(
+                    "Serial output of failed test (`{}`):",
+                    self.stable_path.display()
+                );
+                eprintln!("{}", output);
+            }
+            Err(err) => eprintln!(
+                "Failed to read serial output of failed test from `{}`: {}",
+                self.path.display(),
+                err
+            ),
+        }
+    }
+
+    /// Renames the file of this run to the stable name.
+    ///
+    /// Does nothing if the file doesn't exist, e.g. because QEMU failed to start.
+    fn keep(&self) {
+        if !self.path.exists() {
+            return;
+        }
+        if let Err(err) = fs::rename(&self.path, &self.stable_path) {
+            eprintln!(
+                "Failed to rename serial output `{}` to `{}`: {}",
+                self.path.display(),
+                self.stable_path.display(),
+                err
+            );
+        }
+    }
+}
+
+impl Drop for SerialLog {
+    fn drop(&mut self) {
+        // the file no longer exists if it was renamed by `keep`
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Returns the name of the test that the given executable belongs to.
+///
+/// Cargo appends a `-<hash>` suffix to the names of test executables, which is removed.
+pub fn test_name(executable: &Path) -> String {
+    let file_stem = executable
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    match strip_cargo_hash(&file_stem) {
+        Some(name) => name.to_owned(),
+        None => file_stem,
+    }
+}
+
+/// Strips the `-<16 hex digits>` metadata hash that cargo appends to test executables.
+///
+/// Returns `None` if the given file stem has no such hash.
+fn strip_cargo_hash(file_stem: &str) -> Option<&str> {
+    match file_stem.rsplit_once('-') {
+        Some((name, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Some(name)
+        }
+        _ => None,
+    }
+}
+
+/// Forwards termination signals to a child process.
+#[cfg(unix)]
+mod signal {
+    use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+
+    const SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+    static CHILD_PID: AtomicI32 = AtomicI32::new(0);
+    static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+    /// Restores the previous signal handlers when dropped.
+    pub struct ForwardGuard {
+        previous: [libc::sighandler_t; 2],
+    }
+
+    /// Forwards `SIGINT` and `SIGTERM` to the process with the given PID.
+    ///
+    /// A second signal escalates to `SIGKILL` in case the child does not react.
+    pub fn forward_to(pid: u32) -> ForwardGuard {
+        CHILD_PID.store(pid as i32, Ordering::SeqCst);
+        RECEIVED.store(0, Ordering::SeqCst);
+        let mut previous = [libc::SIG_DFL; 2];
+        for (signal, previous) in SIGNALS.iter().zip(previous.iter_mut()) {
+            let handler = forward as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            *previous = unsafe { libc::signal(*signal, handler) };
+        }
+        ForwardGuard { previous }
+    }
+
+    /// Asks the process with the given PID to exit by sending `SIGTERM`.
+    pub fn terminate(pid: u32) {
+        unsafe { libc::kill(pid as i32, libc::SIGTERM) };
+    }
+
+    extern "C" fn forward(signal: libc::c_int) {
+        let pid = CHILD_PID.load(Ordering::SeqCst);
+        if pid <= 0 {
+            return;
+        }
+        let signal = if RECEIVED.fetch_add(1, Ordering::SeqCst) == 0 {
+            signal
+        } else {
+            libc::SIGKILL
+        };
+        unsafe { libc::kill(pid, signal) };
+    }
+
+    impl Drop for ForwardGuard {
+        fn drop(&mut self) {
+            for (signal, previous) in SIGNALS.iter().zip(self.previous.iter()) {
+                unsafe { libc::signal(*signal, *previous) };
+            }
+            CHILD_PID.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Replaces the `{}` and `{kernel_elf}` placeholders in the given run command argument with
+/// the image path and the path of the kernel executable.
+fn substitute_paths(arg: &str, image_path: &Path, kernel_elf: &Path) -> String {
+    let arg = substitute_path(arg, "{kernel_elf}", kernel_elf);
+    substitute_path(&arg, "{}", image_path)
+}
+
+/// Replaces the given placeholder in the given run command argument with the path.
+///
+/// If the placeholder is part of a comma-separated QEMU option (e.g. `format=raw,file={}`),
+/// commas in the path are doubled, which is how QEMU escapes them in option values.
+fn substitute_path(arg: &str, placeholder: &str, path: &Path) -> String {
+    let path = path.display().to_string();
+    if arg == placeholder {
+        path
+    } else {
+        arg.replace(placeholder, &path.replace(',', ",,"))
+    }
+}
+
+/// Joins the given command with spaces, quoting arguments that contain special characters.
+fn quote_command(command: &[String]) -> String {
+    let quoted: Vec<_> = command.iter().map(|arg| shell_quote(arg)).collect();
+    quoted.join(" ")
+}
+
+/// Writes the given command as a POSIX shell script to the given path.
+fn write_command_file(path: &Path, command: &[String]) -> Result<(), RunError> {
+    let script = format!(
+        "#!/bin/sh\n# Generated by bootimage {}\nexec {} \"$@\"\n",
+        env!("CARGO_PKG_VERSION"),
+        quote_command(command)
+    );
+    let io_context = || IoErrorContext::WriteCommandFile {
+        path: path.to_owned(),
+    };
+    fs::write(path, script).map_err(context(io_context()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+            .map_err(context(io_context()))?;
+    }
+
+    Ok(())
+}
+
+/// Quotes the given argument for a POSIX shell if it contains special characters.
+fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_./=,:+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
 /// Running the disk image failed.
 #[derive(Debug, Error)]
 pub enum RunError {
@@ -101,6 +720,20 @@ pub enum RunError {
     #[error("Failed to read QEMU exit code")]
     NoQemuExitCode,
 
+    /// `--uefi` was passed, but no OVMF firmware path is configured
+    #[error(
+        "Booting with `--uefi` requires the path to the OVMF firmware.\n\n\
+        Set it through the `package.metadata.bootimage.ovmf-path` key in your Cargo.toml."
+    )]
+    OvmfPathNotConfigured,
+
+    /// The initial ramdisk given through `initrd` or `--initrd` does not exist
+    #[error("The initrd `{}` does not exist", path.display())]
+    InitrdNotFound {
+        /// The configured initrd path
+        path: PathBuf,
+    },
+
     /// An I/O error occurred
     #[error("{context}: An I/O error occurred: {error}")]
     Io {
@@ -139,9 +772,217 @@ pub enum IoErrorContext {
     /// Failed to wait for QEMU process
     #[error("Failed to wait for QEMU process")]
     WaitForQemu,
+
+    /// Failed to determine the current working directory
+    #[error("Failed to determine the current working directory")]
+    CurrentDir,
+
+    /// Failed to read the captured serial output
+    #[error("Failed to read serial output from `{}`", path.display())]
+    ReadSerialLog {
+        /// The path of the serial output file
+        path: PathBuf,
+    },
+
+    /// Failed to create the directory of the QEMU debug log
+    #[error("Failed to create directory `{}` for the QEMU debug log", path.display())]
+    CreateDebugLogDir {
+        /// The directory that should be created
+        path: PathBuf,
+    },
+
+    /// Failed to write the run command to a script file
+    #[error("Failed to write run command to `{}`", path.display())]
+    WriteCommandFile {
+        /// The path of the script file
+        path: PathBuf,
+    },
 }
 
 /// Helper function for IO error construction
 fn context(context: IoErrorContext) -> impl FnOnce(io::Error) -> RunError {
     |error| RunError::Io { context, error }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_executables() {
+        let classify = |path: &str| classify_executable(Path::new(path));
+        assert_eq!(
+            classify("target/x86_64-os/debug/deps/basic_boot-1a2b3c4d5e6f7a8b"),
+            ExecutableKind::Test
+        );
+        assert_eq!(
+            classify("/tmp/rustdoctestAbC123/rust_out"),
+            ExecutableKind::Doctest
+        );
+        assert_eq!(
+            classify("/tmp/rustdoctestAbC123/deps/rust_out-1a2b3c4d5e6f7a8b"),
+            ExecutableKind::Doctest
+        );
+        assert_eq!(classify("target/x86_64-os/debug/os"), ExecutableKind::Bin);
+        assert_eq!(
+            classify("target/x86_64-os/debug/my-kernel"),
+            ExecutableKind::Bin
+        );
+        // moved out of the `deps` directory
+        assert_eq!(
+            classify("/tmp/basic_boot-1a2b3c4d5e6f7a8b"),
+            ExecutableKind::Test
+        );
+    }
+
+    #[test]
+    fn strip_hashes() {
+        assert_eq!(
+            strip_cargo_hash("basic_boot-1a2b3c4d5e6f7a8b"),
+            Some("basic_boot")
+        );
+        assert_eq!(
+            strip_cargo_hash("my-kernel-1a2b3c4d5e6f7a8b"),
+            Some("my-kernel")
+        );
+        assert_eq!(strip_cargo_hash("my-kernel"), None);
+        assert_eq!(strip_cargo_hash("kernel-1a2b3c4d5e6f7a8"), None);
+        assert_eq!(strip_cargo_hash("kernel-1a2b3c4d5e6f7a8g"), None);
+        assert_eq!(
+            test_name(Path::new("deps/basic_boot-1a2b3c4d5e6f7a8b")),
+            "basic_boot"
+        );
+    }
+
+    #[test]
+    fn substitute_paths_with_commas() {
+        let image = Path::new("/tmp/my dir,1/bootimage-os.bin");
+        let kernel = Path::new("/tmp/my dir,1/os");
+        assert_eq!(
+            substitute_paths("format=raw,file={}", image, kernel),
+            "format=raw,file=/tmp/my dir,,1/bootimage-os.bin"
+        );
+        assert_eq!(
+            substitute_paths("{}", image, kernel),
+            "/tmp/my dir,1/bootimage-os.bin"
+        );
+        assert_eq!(
+            substitute_paths("{kernel_elf}", image, kernel),
+            "/tmp/my dir,1/os"
+        );
+        assert_eq!(
+            substitute_paths("file={kernel_elf}", image, kernel),
+            "file=/tmp/my dir,,1/os"
+        );
+    }
+
+    #[test]
+    fn shell_quoting() {
+        assert_eq!(
+            shell_quote("format=raw,file=/a/b.bin"),
+            "format=raw,file=/a/b.bin"
+        );
+        assert_eq!(shell_quote("/tmp/my dir/os"), "'/tmp/my dir/os'");
+        assert_eq!(
+            shell_quote("file=/tmp/my dir,,1/os"),
+            "'file=/tmp/my dir,,1/os'"
+        );
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(
+            quote_command(&args(&["qemu", "-drive", "file=/my dir/a.bin"])),
+            "qemu -drive 'file=/my dir/a.bin'"
+        );
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&arg| arg.to_owned()).collect()
+    }
+
+    #[test]
+    fn dedup_keeps_last_single_valued_option() {
+        let command = args(&[
+            "qemu", "-m", "256M", "-display", "none", "-m", "1G", "-cpu", "max",
+        ]);
+        assert_eq!(
+            dedup_qemu_args(command),
+            args(&["qemu", "-display", "none", "-m", "1G", "-cpu", "max"])
+        );
+    }
+
+    #[test]
+    fn dedup_keeps_other_options() {
+        let command = args(&[
+            "qemu",
+            "-machine",
+            "q35",
+            "-device",
+            "a",
+            "-machine",
+            "accel=kvm",
+            "-device",
+            "b",
+        ]);
+        assert_eq!(dedup_qemu_args(command.clone()), command);
+    }
+
+    #[test]
+    fn dedup_ignores_option_values() {
+        // `-m` as the program or as a value of another option is not an option
+        let command = args(&["-m", "-name", "-m", "-m", "1G", "-m"]);
+        assert_eq!(dedup_qemu_args(command.clone()), command);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_capturing_substitutes_paths() {
+        let dir = std::env::temp_dir().join(format!("bootimage-capture-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // writes the substituted image and kernel paths to the serial output file (`$` is
+        // escaped as `$$` in the config)
+        let script = r#"for arg; do case $arg in file:*) out=${arg#file:};; esac; done; echo "$0 $1" > "$out"; exit 3"#;
+        fs::write(
+            dir.join("Cargo.toml"),
+            format!(
+                "[package]\nname = \"kernel\"\nversion = \"0.1.0\"\n\n\
+                 [package.metadata.bootimage]\n\
+                 run-command = [\"sh\", \"-c\", '{}', \"{{}}\", \"{{kernel_elf}}\"]\n",
+                script.replace('$', "$$")
+            ),
+        )
+        .unwrap();
+        std::env::set_var("BOOTIMAGE_NO_USER_CONFIG", "1");
+        let config = crate::config::read_config(&dir.join("Cargo.toml")).unwrap();
+
+        let result = run_capturing(
+            &config,
+            Path::new("/tmp/bootimage-kernel.bin"),
+            Path::new("/tmp/kernel"),
+            Some("x86_64-unknown-none"),
+            Duration::from_secs(10),
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        let (exit_code, serial_output) = result.unwrap();
+        assert_eq!(exit_code, 3);
+        assert_eq!(serial_output, "/tmp/bootimage-kernel.bin /tmp/kernel\n");
+    }
+
+    #[test]
+    fn serial_log_is_removed_unless_kept() {
+        let image_path = std::env::temp_dir().join(format!("bootimage-test-{}.bin", process::id()));
+
+        let log = SerialLog::new(&image_path);
+        fs::write(&log.path, "output").unwrap();
+        let path = log.path.clone();
+        drop(log);
+        assert!(!path.exists());
+
+        let log = SerialLog::new(&image_path);
+        log.keep();
+        assert!(!log.stable_path.exists());
+        fs::write(&log.path, "output").unwrap();
+        log.keep();
+        assert_eq!(fs::read_to_string(&log.stable_path).unwrap(), "output");
+        fs::remove_file(&log.stable_path).unwrap();
+    }
+}